@@ -0,0 +1,509 @@
+use std::time::Duration;
+use chrono::NaiveDateTime;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::{Error as ThisError};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Framed;
+use uuid::Uuid;
+use frame_codec::{CodecError, FrameCodec};
+use net::{ClientRequest, Command, CommandError, CommandResponseValue, CommandResult, ErrorKind, HealthReport, Hello, HelloResponse, Priority, Project, ServerResponse, TagMatch, Task, TaskTree};
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Framing error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("Server didn't answer within {0:?}")]
+    Timeout(Duration),
+
+    #[error("Connection lost")]
+    ConnectionClosed,
+
+    // The server turned the request away without running it and hangs up next.
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Server refused the connection: {0}")]
+    Handshake(String),
+
+    // The server refused the request as a whole, none of its commands ran.
+    #[error("Request rejected: {0}")]
+    Rejected(CommandError),
+
+    #[error("Command #{index} failed: {error}")]
+    Command{index: usize, error: CommandError},
+
+    #[error("Server didn't answer command #{0}")]
+    MissingResponse(usize),
+
+    #[error("Server answered with a response for another command")]
+    UnexpectedResponse,
+
+}
+
+impl Error {
+
+    // Errors after which the connection is dropped and a new one is opened on the next call.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, Error::Io(_) | Error::Codec(_) | Error::Serialization(_) | Error::Timeout(_) | Error::ConnectionClosed | Error::ShuttingDown)
+    }
+
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub addr: String,
+    // Applies to connecting and to every request/response exchange on its own.
+    pub timeout: Duration,
+    // How often connecting is tried before giving up, waiting `reconnect_delay` after the first
+    // failure and twice as long after every further one.
+    pub connect_attempts: u32,
+    pub reconnect_delay: Duration,
+    // Largest response frame accepted, in bytes.
+    pub max_frame_size: usize,
+}
+
+impl ClientConfig {
+
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            timeout: Duration::from_secs(10),
+            connect_attempts: 3,
+            reconnect_delay: Duration::from_millis(200),
+            max_frame_size: 64 * 1024 * 1024,
+        }
+    }
+
+}
+
+type Connection<Id, T> = Framed<TcpStream, FrameCodec<ServerResponse<T, Id>, ClientRequest<Id>>>;
+
+// Async client for the task protocol. The connection is opened lazily and reopened after it's lost.
+// Requests the server turned away while shutting down and requests made only of read-only commands
+// are retried once on a fresh connection, anything that mutates fails with the connection error instead: it may or may not have run, and its idempotency
+// key only helps while the server still has the saved response, which a restart can lose.
+pub struct Client<Id = i32, T = Task> {
+    config: ClientConfig,
+    connection: Option<Connection<Id, T>>,
+    server: Option<Hello>,
+}
+
+impl<Id, T> Client<Id, T>
+where
+    Id: Serialize + DeserializeOwned + Clone,
+    T: DeserializeOwned + Clone,
+{
+
+    // Connects right away, so a wrong address or an incompatible server shows up here.
+    pub async fn connect(config: ClientConfig) -> Result<Self, Error> {
+        let mut client = Self {
+            config,
+            connection: None,
+            server: None,
+        };
+        client.reconnect().await?;
+        Ok(client)
+    }
+
+    // What the server announced in the handshake of the current connection.
+    pub fn server(&self) -> Option<&Hello> {
+        self.server.as_ref()
+    }
+
+    async fn open(&self) -> Result<(Connection<Id, T>, Hello), Error> {
+        let stream = TcpStream::connect(&self.config.addr);
+        let stream = timeout(self.config.timeout, stream).await.map_err(|_| Error::Timeout(self.config.timeout))??;
+
+        let codec = FrameCodec::<HelloResponse, Hello>::with_max_frame_size(self.config.max_frame_size);
+        let mut framed = Framed::new(stream, codec);
+        let handshake = async {
+            framed.send(Hello::new()).await?;
+            let frame = framed.next().await.ok_or(Error::ConnectionClosed)?;
+            Ok::<_, Error>(frame??)
+        };
+        let response = timeout(self.config.timeout, handshake).await.map_err(|_| Error::Timeout(self.config.timeout))??;
+        let server = match response {
+            HelloResponse::Accepted(server) => server,
+            HelloResponse::Rejected { reason, .. } => return Err(Error::Handshake(reason)),
+        };
+        let framed = framed.map_codec(|codec| FrameCodec::with_max_frame_size(codec.max_frame_size()));
+        Ok((framed, server))
+    }
+
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        self.connection = None;
+        let mut delay = self.config.reconnect_delay;
+        let mut attempt = 1;
+        loop {
+            match self.open().await {
+                Ok((connection, server)) => {
+                    self.connection = Some(connection);
+                    self.server = Some(server);
+                    return Ok(());
+                },
+                Err(e) if e.is_connection_error() && attempt < self.config.connect_attempts => {
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn exchange(&mut self, rq: &ClientRequest<Id>) -> Result<ServerResponse<T, Id>, Error> {
+        if self.connection.is_none() {
+            self.reconnect().await?;
+        }
+        let Some(connection) = self.connection.as_mut() else {
+            return Err(Error::ConnectionClosed);
+        };
+        let exchange = async {
+            connection.send(rq).await?;
+            let frame = connection.next().await.ok_or(Error::ConnectionClosed)?;
+            Ok::<_, Error>(frame??)
+        };
+        let result = timeout(self.config.timeout, exchange)
+            .await
+            .unwrap_or(Err(Error::Timeout(self.config.timeout)))
+            .and_then(|rs| match rs.error() {
+                Some(e) if e.kind == ErrorKind::ShuttingDown => Err(Error::ShuttingDown),
+                _ => Ok(rs),
+            });
+        if let Err(e) = &result && e.is_connection_error() {
+            // A late answer would be taken for the next request's, the stream is unusable now
+            self.connection = None;
+        }
+        result
+    }
+
+    // Sends a whole request and hands back the raw response.
+    pub async fn send(&mut self, rq: &ClientRequest<Id>) -> Result<ServerResponse<T, Id>, Error> {
+        let keyed;
+        let rq = if rq.is_read_only() || rq.idempotency_key().is_some() {
+            rq
+        } else {
+            keyed = rq.clone().with_idempotency_key(&Uuid::new_v4().to_string());
+            &keyed
+        };
+        let rs = match self.exchange(rq).await {
+            // It never ran, so even a mutating request is safe to send again
+            Err(Error::ShuttingDown) => self.exchange(rq).await?,
+            Err(e) if e.is_connection_error() && rq.is_read_only() => self.exchange(rq).await?,
+            result => result?,
+        };
+        if let Some(e) = rs.error() {
+            return Err(Error::Rejected(e.clone()));
+        }
+        Ok(rs)
+    }
+
+    // Runs the commands independently of each other, one result per command in the same order.
+    pub async fn batch(&mut self, commands: &[Command<Id>]) -> Result<Vec<CommandResult<T, Id>>, Error> {
+        let rs = self.send(&ClientRequest::new(commands)).await?;
+        (0..commands.len())
+            .map(|index| rs.get(index).cloned().ok_or(Error::MissingResponse(index)))
+            .collect()
+    }
+
+    // Runs the commands in one transaction, either all of them succeed or none has any effect.
+    pub async fn atomic_batch(&mut self, commands: &[Command<Id>]) -> Result<Vec<CommandResponseValue<T, Id>>, Error> {
+        let rs = self.send(&ClientRequest::atomic(commands)).await?;
+        let mut values = Vec::with_capacity(commands.len());
+        for index in 0..commands.len() {
+            match rs.get(index) {
+                Some(CommandResult::Success(value)) => values.push(value.clone()),
+                Some(CommandResult::Error(error)) => return Err(Error::Command { index, error: error.clone() }),
+                // Keep looking for the command that actually failed
+                Some(CommandResult::Aborted { .. }) => {},
+                None => return Err(Error::MissingResponse(index)),
+            }
+        }
+        if values.len() != commands.len() {
+            return Err(Error::UnexpectedResponse);
+        }
+        Ok(values)
+    }
+
+    async fn single(&mut self, command: Command<Id>) -> Result<CommandResponseValue<T, Id>, Error> {
+        match self.batch(&[command]).await?.pop() {
+            Some(CommandResult::Success(value)) => Ok(value),
+            Some(CommandResult::Error(error)) => Err(Error::Command { index: 0, error }),
+            Some(CommandResult::Aborted { .. }) => Err(Error::UnexpectedResponse),
+            None => Err(Error::MissingResponse(0)),
+        }
+    }
+
+    pub async fn new_task(&mut self, title: &str, priority: Priority) -> Result<T, Error> {
+        match self.single(Command::NewTask { title: title.to_string(), priority, due_at: None, project_id: None }).await? {
+            CommandResponseValue::NewTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // `due_at` is in UTC, like `set_due_date`.
+    pub async fn new_task_due(&mut self, title: &str, priority: Priority, due_at: NaiveDateTime) -> Result<T, Error> {
+        let command = Command::NewTask { title: title.to_string(), priority, due_at: Some(due_at), project_id: None };
+        match self.single(command).await? {
+            CommandResponseValue::NewTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn new_task_in(&mut self, title: &str, priority: Priority, project_id: Id) -> Result<T, Error> {
+        let command = Command::NewTask { title: title.to_string(), priority, due_at: None, project_id: Some(project_id) };
+        match self.single(command).await? {
+            CommandResponseValue::NewTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn pending_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::PendingTasks).await? {
+            CommandResponseValue::PendingTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn done_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::DoneTasks).await? {
+            CommandResponseValue::DoneTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn archived_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::ArchivedTasks).await? {
+            CommandResponseValue::ArchivedTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn task(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::QueryTaskById(task_id)).await? {
+            CommandResponseValue::QueryTaskById(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn mark_done(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::MarkTaskDone(task_id)).await? {
+            CommandResponseValue::MarkTaskDone(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn reopen(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::ReopenTask(task_id)).await? {
+            CommandResponseValue::ReopenTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn edit_title(&mut self, task_id: Id, new_title: &str) -> Result<T, Error> {
+        match self.single(Command::EditTaskTitle { task_id, new_title: new_title.to_string() }).await? {
+            CommandResponseValue::EditTaskTitle(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn edit_priority(&mut self, task_id: Id, priority: Priority) -> Result<T, Error> {
+        match self.single(Command::EditTaskPriority { task_id, priority }).await? {
+            CommandResponseValue::EditTaskPriority(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn delete_task(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::DeleteTask(task_id)).await? {
+            CommandResponseValue::DeleteTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn archive(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::ArchiveTask(task_id)).await? {
+            CommandResponseValue::ArchiveTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn unarchive(&mut self, task_id: Id) -> Result<T, Error> {
+        match self.single(Command::UnarchiveTask(task_id)).await? {
+            CommandResponseValue::UnarchiveTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // `None` clears the due date. Times are UTC, like every time the server keeps.
+    pub async fn set_due_date(&mut self, task_id: Id, due_at: Option<NaiveDateTime>) -> Result<T, Error> {
+        match self.single(Command::SetDueDate { task_id, due_at }).await? {
+            CommandResponseValue::SetDueDate(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn overdue_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::OverdueTasks).await? {
+            CommandResponseValue::OverdueTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn due_within(&mut self, days: u32) -> Result<Vec<T>, Error> {
+        match self.single(Command::DueWithin { days }).await? {
+            CommandResponseValue::DueWithin(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn add_tag(&mut self, task_id: Id, tag: &str) -> Result<T, Error> {
+        match self.single(Command::AddTag { task_id, tag: tag.to_string() }).await? {
+            CommandResponseValue::AddTag(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn remove_tag(&mut self, task_id: Id, tag: &str) -> Result<T, Error> {
+        match self.single(Command::RemoveTag { task_id, tag: tag.to_string() }).await? {
+            CommandResponseValue::RemoveTag(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn tasks_with_tag(&mut self, tags: &[&str], matching: TagMatch) -> Result<Vec<T>, Error> {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        match self.single(Command::TasksWithTag { tags, matching }).await? {
+            CommandResponseValue::TasksWithTag(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn new_project(&mut self, name: &str) -> Result<Project<Id>, Error> {
+        match self.single(Command::NewProject { name: name.to_string() }).await? {
+            CommandResponseValue::NewProject(project) => Ok(project),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn rename_project(&mut self, project_id: Id, name: &str) -> Result<Project<Id>, Error> {
+        match self.single(Command::RenameProject { project_id, name: name.to_string() }).await? {
+            CommandResponseValue::RenameProject(project) => Ok(project),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // The project's tasks stay, outside of any project.
+    pub async fn delete_project(&mut self, project_id: Id) -> Result<Project<Id>, Error> {
+        match self.single(Command::DeleteProject(project_id)).await? {
+            CommandResponseValue::DeleteProject(project) => Ok(project),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn projects(&mut self) -> Result<Vec<Project<Id>>, Error> {
+        match self.single(Command::Projects).await? {
+            CommandResponseValue::Projects(projects) => Ok(projects),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // `None` takes the task out of its project.
+    pub async fn move_task(&mut self, task_id: Id, project_id: Option<Id>) -> Result<T, Error> {
+        match self.single(Command::MoveTask { task_id, project_id }).await? {
+            CommandResponseValue::MoveTask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn project_pending_tasks(&mut self, project_id: Id) -> Result<Vec<T>, Error> {
+        match self.single(Command::ProjectPendingTasks(project_id)).await? {
+            CommandResponseValue::ProjectPendingTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn project_done_tasks(&mut self, project_id: Id) -> Result<Vec<T>, Error> {
+        match self.single(Command::ProjectDoneTasks(project_id)).await? {
+            CommandResponseValue::ProjectDoneTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn add_subtask(&mut self, parent_id: Id, title: &str, priority: Priority) -> Result<T, Error> {
+        match self.single(Command::AddSubtask { parent_id, title: title.to_string(), priority }).await? {
+            CommandResponseValue::AddSubtask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // `None` makes the task a top-level one again.
+    pub async fn move_subtask(&mut self, task_id: Id, parent_id: Option<Id>) -> Result<T, Error> {
+        match self.single(Command::MoveSubtask { task_id, parent_id }).await? {
+            CommandResponseValue::MoveSubtask(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn task_tree(&mut self, task_id: Id) -> Result<TaskTree<T>, Error> {
+        match self.single(Command::TaskTree(task_id)).await? {
+            CommandResponseValue::TaskTree(tree) => Ok(tree),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // `task_id` can't be marked done before `blocked_by` is.
+    pub async fn add_dependency(&mut self, task_id: Id, blocked_by: Id) -> Result<T, Error> {
+        match self.single(Command::AddDependency { task_id, blocked_by }).await? {
+            CommandResponseValue::AddDependency(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn remove_dependency(&mut self, task_id: Id, blocked_by: Id) -> Result<T, Error> {
+        match self.single(Command::RemoveDependency { task_id, blocked_by }).await? {
+            CommandResponseValue::RemoveDependency(task) => Ok(task),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn blocked_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::BlockedTasks).await? {
+            CommandResponseValue::BlockedTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn ready_tasks(&mut self) -> Result<Vec<T>, Error> {
+        match self.single(Command::ReadyTasks).await? {
+            CommandResponseValue::ReadyTasks(tasks) => Ok(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        match self.single(Command::Ping).await? {
+            CommandResponseValue::Pong => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    // The server's readiness checks; a server that isn't ready still answers, see `HealthReport::is_ready`.
+    pub async fn health(&mut self) -> Result<HealthReport, Error> {
+        match self.single(Command::Health).await? {
+            CommandResponseValue::Health(report) => Ok(report),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+}
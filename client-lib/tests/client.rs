@@ -0,0 +1,337 @@
+use std::time::Duration;
+use chrono::{TimeDelta, Utc};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+use frame_codec::FrameCodec;
+use net::{ClientRequest, Command, CommandResponseValue, CommandResult, ErrorKind, Hello, HelloResponse, Priority, ServerResponse, TagMatch};
+use task_client::{Client, ClientConfig, Error};
+use task_store::{handle_connection, serve, MemoryTaskStore, Metrics, ServerConfig};
+
+async fn start_server() -> String {
+    start_server_with(ServerConfig::default()).await
+}
+
+async fn start_server_with(server_config: ServerConfig) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, MemoryTaskStore::new(), server_config, Metrics::new()));
+    addr.to_string()
+}
+
+// Completes the handshake on the first connection and then hangs up, like a server that restarts.
+async fn start_flaky_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(stream, FrameCodec::<Hello, HelloResponse>::new());
+        framed.next().await.unwrap().unwrap().unwrap();
+        framed.send(HelloResponse::Accepted(Hello::new())).await.unwrap();
+        drop(framed);
+
+        let db = MemoryTaskStore::new();
+        let metrics = Metrics::new();
+        loop {
+            let (stream, addr) = listener.accept().await.unwrap();
+            tokio::spawn(handle_connection(stream, addr, db.clone(), ServerConfig::default(), metrics.clone()));
+        }
+    });
+    addr.to_string()
+}
+
+fn config(addr: &str) -> ClientConfig {
+    let mut config = ClientConfig::new(addr);
+    config.timeout = Duration::from_secs(2);
+    config.reconnect_delay = Duration::from_millis(10);
+    config
+}
+
+#[tokio::test]
+async fn typed_methods() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let task = client.new_task("write tests", Priority::Urgent).await.unwrap();
+    assert_eq!(client.pending_tasks().await.unwrap().len(), 1);
+
+    let task = client.mark_done(task.id).await.unwrap();
+    assert!(task.completed);
+    assert_eq!(client.done_tasks().await.unwrap().len(), 1);
+
+    let task = client.edit_title(task.id, "write more tests").await.unwrap();
+    assert_eq!(client.task(task.id).await.unwrap().title, "write more tests");
+
+    client.delete_task(task.id).await.unwrap();
+    match client.task(task.id).await {
+        Err(Error::Command { index: 0, error }) => assert_eq!(error.kind, ErrorKind::NotFound),
+        other => panic!("expected NotFound, got {:?}", other.map(|task| task.id)),
+    }
+}
+
+#[tokio::test]
+async fn due_dates() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+    let now = Utc::now().naive_utc();
+
+    let late = client.new_task_due("late", Priority::Urgent, now - TimeDelta::days(1)).await.unwrap();
+    assert_eq!(client.task(late.id).await.unwrap().due_at, Some(now - TimeDelta::days(1)));
+    let soon = client.new_task("soon", Priority::Regular).await.unwrap();
+    let later = client.new_task("later", Priority::Low).await.unwrap();
+    let done = client.new_task("done", Priority::Low).await.unwrap();
+    client.new_task("whenever", Priority::Low).await.unwrap();
+    client.set_due_date(soon.id, Some(now + TimeDelta::days(2))).await.unwrap();
+    client.set_due_date(later.id, Some(now + TimeDelta::days(10))).await.unwrap();
+    client.set_due_date(done.id, Some(now - TimeDelta::days(2))).await.unwrap();
+    client.mark_done(done.id).await.unwrap();
+
+    let ids = |tasks: Vec<net::Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+    assert_eq!(ids(client.overdue_tasks().await.unwrap()), vec![late.id]);
+    assert_eq!(ids(client.due_within(3).await.unwrap()), vec![soon.id]);
+    assert_eq!(ids(client.due_within(30).await.unwrap()), vec![soon.id, later.id]);
+
+    let cleared = client.set_due_date(later.id, None).await.unwrap();
+    assert!(cleared.due_at.is_none());
+    assert_eq!(ids(client.due_within(30).await.unwrap()), vec![soon.id]);
+}
+
+#[tokio::test]
+async fn tags() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let backend = client.new_task("fix the pool", Priority::Urgent).await.unwrap();
+    let docs = client.new_task("document the pool", Priority::Low).await.unwrap();
+    client.add_tag(backend.id, "backend").await.unwrap();
+    client.add_tag(backend.id, " Ops ").await.unwrap();
+    let backend = client.add_tag(backend.id, "ops").await.unwrap();
+    assert_eq!(backend.tags, vec!["backend", "ops"]);
+    client.add_tag(docs.id, "docs").await.unwrap();
+    client.add_tag(docs.id, "backend").await.unwrap();
+
+    let ids = |tasks: Vec<net::Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+    assert_eq!(ids(client.tasks_with_tag(&["backend", "ops"], TagMatch::All).await.unwrap()), vec![backend.id]);
+    assert_eq!(ids(client.tasks_with_tag(&["ops", "docs"], TagMatch::Any).await.unwrap()), vec![backend.id, docs.id]);
+
+    let docs = client.remove_tag(docs.id, "backend").await.unwrap();
+    assert_eq!(docs.tags, vec!["docs"]);
+    match client.add_tag(docs.id, "two words").await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::ValidationFailed),
+        other => panic!("expected ValidationFailed, got {:?}", other.map(|task| task.id)),
+    }
+}
+
+#[tokio::test]
+async fn projects() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let home = client.new_project("Home").await.unwrap();
+    let work = client.new_project("Work").await.unwrap();
+    match client.new_project(" Home ").await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::Conflict),
+        other => panic!("expected Conflict, got {:?}", other.map(|project| project.id)),
+    }
+    let groceries = client.new_task_in("groceries", Priority::Regular, home.id).await.unwrap();
+    let report = client.new_task("report", Priority::Urgent).await.unwrap();
+    let report = client.move_task(report.id, Some(work.id)).await.unwrap();
+    assert_eq!(report.project_id, Some(work.id));
+    client.mark_done(report.id).await.unwrap();
+
+    let ids = |tasks: Vec<net::Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+    assert_eq!(ids(client.project_pending_tasks(home.id).await.unwrap()), vec![groceries.id]);
+    assert!(client.project_pending_tasks(work.id).await.unwrap().is_empty());
+    assert_eq!(ids(client.project_done_tasks(work.id).await.unwrap()), vec![report.id]);
+
+    client.rename_project(work.id, "Office").await.unwrap();
+    client.delete_project(home.id).await.unwrap();
+    let names = client.projects().await.unwrap().into_iter().map(|project| project.name).collect::<Vec<_>>();
+    assert_eq!(names, vec!["Office"]);
+    assert_eq!(client.task(groceries.id).await.unwrap().project_id, None);
+    match client.move_task(groceries.id, Some(home.id)).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::NotFound),
+        other => panic!("expected NotFound, got {:?}", other.map(|task| task.id)),
+    }
+}
+
+#[tokio::test]
+async fn subtasks() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let home = client.new_project("Home").await.unwrap();
+    let move_out = client.new_task_in("move out", Priority::Urgent, home.id).await.unwrap();
+    let pack = client.add_subtask(move_out.id, "pack", Priority::Regular).await.unwrap();
+    let books = client.add_subtask(pack.id, "books", Priority::Low).await.unwrap();
+    let clean = client.add_subtask(move_out.id, "clean", Priority::Regular).await.unwrap();
+    assert_eq!(books.project_id, Some(home.id));
+
+    let tree = client.task_tree(move_out.id).await.unwrap();
+    let shape = tree.flatten().into_iter().map(|(depth, task)| (depth, task.id)).collect::<Vec<_>>();
+    assert_eq!(shape, vec![(0, move_out.id), (1, pack.id), (2, books.id), (1, clean.id)]);
+
+    match client.mark_done(pack.id).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::Conflict),
+        other => panic!("expected Conflict, got {:?}", other.map(|task| task.id)),
+    }
+    match client.move_subtask(move_out.id, Some(books.id)).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::ValidationFailed),
+        other => panic!("expected ValidationFailed, got {:?}", other.map(|task| task.id)),
+    }
+    let clean = client.move_subtask(clean.id, None).await.unwrap();
+    assert_eq!(clean.parent_id, None);
+
+    client.delete_task(move_out.id).await.unwrap();
+    assert_eq!(client.pending_tasks().await.unwrap().len(), 1);
+    match client.task(books.id).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::NotFound),
+        other => panic!("expected NotFound, got {:?}", other.map(|task| task.id)),
+    }
+}
+
+#[tokio::test]
+async fn finishing_the_last_subtask_completes_the_parent() {
+    let addr = start_server_with(ServerConfig { auto_complete_parents: true, ..ServerConfig::default() }).await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let move_out = client.new_task("move out", Priority::Urgent).await.unwrap();
+    let pack = client.add_subtask(move_out.id, "pack", Priority::Regular).await.unwrap();
+    let books = client.add_subtask(pack.id, "books", Priority::Low).await.unwrap();
+    let clean = client.add_subtask(move_out.id, "clean", Priority::Regular).await.unwrap();
+
+    client.mark_done(books.id).await.unwrap();
+    assert!(client.task(pack.id).await.unwrap().completed);
+    assert!(!client.task(move_out.id).await.unwrap().completed);
+    client.mark_done(clean.id).await.unwrap();
+    assert!(client.task(move_out.id).await.unwrap().completed);
+}
+
+#[tokio::test]
+async fn dependencies() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let build = client.new_task("build", Priority::Regular).await.unwrap();
+    let test = client.new_task("test", Priority::Regular).await.unwrap();
+    let release = client.new_task("release", Priority::Urgent).await.unwrap();
+    client.add_dependency(test.id, build.id).await.unwrap();
+    let release = client.add_dependency(release.id, test.id).await.unwrap();
+    assert_eq!(release.blocked_by, vec![test.id]);
+    match client.add_dependency(build.id, release.id).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::ValidationFailed),
+        other => panic!("expected ValidationFailed, got {:?}", other.map(|task| task.id)),
+    }
+
+    let ids = |tasks: Vec<net::Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+    assert_eq!(ids(client.ready_tasks().await.unwrap()), vec![build.id]);
+    assert_eq!(ids(client.blocked_tasks().await.unwrap()), vec![test.id, release.id]);
+    match client.mark_done(test.id).await {
+        Err(Error::Command { error, .. }) => assert_eq!(error.kind, ErrorKind::Conflict),
+        other => panic!("expected Conflict, got {:?}", other.map(|task| task.id)),
+    }
+
+    client.mark_done(build.id).await.unwrap();
+    assert_eq!(ids(client.ready_tasks().await.unwrap()), vec![test.id]);
+    client.remove_dependency(release.id, test.id).await.unwrap();
+    client.delete_task(build.id).await.unwrap();
+    assert!(client.task(test.id).await.unwrap().blocked_by.is_empty());
+    assert!(client.blocked_tasks().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn ping_and_health() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    client.ping().await.unwrap();
+    let report = client.health().await.unwrap();
+    assert!(report.is_ready());
+}
+
+#[tokio::test]
+async fn batches() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let results = client
+        .batch(&[Command::NewTask { title: "a".to_string(), priority: Priority::Low, due_at: None, project_id: None }, Command::MarkTaskDone(42)])
+        .await
+        .unwrap();
+    assert!(matches!(results[0], CommandResult::Success(CommandResponseValue::NewTask(_))));
+    assert!(matches!(results[1], CommandResult::Error(_)));
+
+    let atomic = client
+        .atomic_batch(&[Command::NewTask { title: "b".to_string(), priority: Priority::Low, due_at: None, project_id: None }, Command::MarkTaskDone(42)])
+        .await;
+    assert!(matches!(atomic, Err(Error::Command { index: 1, .. })));
+    assert_eq!(client.pending_tasks().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn read_only_requests_are_retried_after_a_lost_connection() {
+    let addr = start_flaky_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    assert!(client.pending_tasks().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn mutating_requests_are_not_resent_after_a_lost_connection() {
+    let addr = start_flaky_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    match client.new_task("maybe", Priority::Low).await {
+        Err(e) => assert!(e.is_connection_error(), "{}", e),
+        Ok(task) => panic!("expected a connection error, got task {}", task.id),
+    }
+    // Read-only requests reconnect, and the lost one never reached a server
+    assert!(client.pending_tasks().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn requests_with_the_same_idempotency_key_run_once() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let rq = ClientRequest::new(&[Command::NewTask { title: "once".to_string(), priority: Priority::Low, due_at: None, project_id: None }])
+        .with_idempotency_key("same-key");
+    let first = client.send(&rq).await.unwrap();
+    let second = client.send(&rq).await.unwrap();
+    let id = |rs: &ServerResponse| match rs.get(0) {
+        Some(CommandResult::Success(CommandResponseValue::NewTask(task))) => task.id,
+        _ => panic!("expected a new task"),
+    };
+    assert_eq!(id(&first), id(&second));
+    assert_eq!(client.pending_tasks().await.unwrap().len(), 1);
+
+    // A different key is a different request
+    client.send(&rq.clone().with_idempotency_key("other-key")).await.unwrap();
+    assert_eq!(client.pending_tasks().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn an_idempotency_key_only_replays_its_own_request() {
+    let addr = start_server().await;
+    let mut client: Client = Client::connect(config(&addr)).await.unwrap();
+    let mut other: Client = Client::connect(config(&addr)).await.unwrap();
+
+    let new_task = |title: &str| Command::NewTask { title: title.to_string(), priority: Priority::Low, due_at: None, project_id: None };
+    client.send(&ClientRequest::new(&[new_task("mine")]).with_idempotency_key("shared-key")).await.unwrap();
+    match other.send(&ClientRequest::new(&[new_task("theirs")]).with_idempotency_key("shared-key")).await {
+        Err(Error::Rejected(error)) => assert_eq!(error.kind, ErrorKind::Conflict),
+        other => panic!("expected a Conflict rejection, got {:?}", other.map(|_| ())),
+    }
+    let titles: Vec<_> = client.pending_tasks().await.unwrap().into_iter().map(|task| task.title).collect();
+    assert_eq!(titles, ["mine"]);
+}
+
+#[tokio::test]
+async fn connecting_gives_up_after_the_configured_attempts() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    let result = Client::<i32>::connect(config(&addr)).await;
+    assert!(matches!(result, Err(Error::Io(_))));
+}
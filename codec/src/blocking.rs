@@ -0,0 +1,93 @@
+use std::io::{Read, Write};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+// Blocking counterpart of `tokio_util::codec::Framed` for `std::io` streams, so the blocking
+// clients frame messages with the very same codec as the server.
+pub struct BlockingFramed<S, C> {
+    stream: S,
+    codec: C,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+}
+
+impl<S, C> BlockingFramed<S, C> {
+
+    pub fn new(stream: S, codec: C) -> Self {
+        Self {
+            stream,
+            codec,
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    // Switches to another codec, e.g. after a handshake, keeping anything already buffered.
+    pub fn map_codec<D, F>(self, f: F) -> BlockingFramed<S, D>
+    where
+        F: FnOnce(C) -> D,
+    {
+        BlockingFramed {
+            stream: self.stream,
+            codec: f(self.codec),
+            read_buffer: self.read_buffer,
+            write_buffer: self.write_buffer,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+}
+
+impl<S: Write, C> BlockingFramed<S, C> {
+
+    pub fn send<I>(&mut self, item: I) -> Result<(), C::Error>
+    where
+        C: Encoder<I>,
+    {
+        self.write_buffer.clear();
+        self.codec.encode(item, &mut self.write_buffer)?;
+        self.stream.write_all(&self.write_buffer)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+}
+
+impl<S: Read, C: Decoder> BlockingFramed<S, C> {
+
+    // Blocks until a whole frame is in, `None` once the peer closed the stream between frames.
+    pub fn recv(&mut self) -> Result<Option<C::Item>, C::Error> {
+        loop {
+            if let Some(item) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(Some(item));
+            }
+            let start = self.read_buffer.len();
+            self.read_buffer.resize(start + READ_CHUNK, 0);
+            let read = self.stream.read(&mut self.read_buffer[start..]);
+            let n = match read {
+                Ok(n) => n,
+                Err(e) => {
+                    self.read_buffer.truncate(start);
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e.into());
+                },
+            };
+            self.read_buffer.truncate(start + n);
+            if n == 0 {
+                return self.codec.decode_eof(&mut self.read_buffer);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,120 @@
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use bincode::Options;
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::{Error as ThisError};
+use tokio_util::codec::{Decoder, Encoder};
+
+mod blocking;
+
+pub use blocking::BlockingFramed;
+
+// Every frame is a big-endian u32 length followed by that many bytes of bincode.
+const LENGTH_PREFIX: usize = 4;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(ThisError, Debug)]
+pub enum CodecError {
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("Frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge{len: usize, max: usize},
+
+}
+
+// A frame that was read whole but didn't deserialize into the expected message. The stream is
+// still in sync after one, so the peer can be told and the connection kept.
+pub type Decoded<T> = Result<T, bincode::Error>;
+
+// Length-prefixed bincode frames, decoding `In` and encoding `Out`. A frame announcing more than
+// `max_frame_size` bytes is an error before anything is allocated for it.
+pub struct FrameCodec<In, Out> {
+    max_frame_size: usize,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> FrameCodec<In, Out> {
+
+    pub fn new() -> Self {
+        Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            _messages: PhantomData,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+}
+
+impl<In, Out> Default for FrameCodec<In, Out> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<In, Out> Clone for FrameCodec<In, Out> {
+    fn clone(&self) -> Self {
+        Self::with_max_frame_size(self.max_frame_size)
+    }
+}
+
+impl<In: DeserializeOwned, Out> Decoder for FrameCodec<In, Out> {
+    type Item = Decoded<In>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LENGTH_PREFIX {
+            return Ok(None);
+        }
+        let mut length_buffer = [0u8; LENGTH_PREFIX];
+        length_buffer.copy_from_slice(&src[..LENGTH_PREFIX]);
+        let len = u32::from_be_bytes(length_buffer) as usize;
+        if len > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge { len, max: self.max_frame_size });
+        }
+        if src.len() < LENGTH_PREFIX + len {
+            // Make room for the rest of the frame so it arrives in as few reads as possible
+            src.reserve(LENGTH_PREFIX + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_PREFIX);
+        let frame = src.split_to(len);
+        // Same encoding as `bincode::serialize`, but a length field inside the frame can't make
+        // the decoder allocate more than the frame could possibly hold.
+        let message = bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.max_frame_size as u64)
+            .deserialize(&frame[..]);
+        Ok(Some(message))
+    }
+}
+
+// Takes `Out` by value or by reference, callers don't have to clone what they keep using.
+impl<In, Out: Serialize, T: Borrow<Out>> Encoder<T> for FrameCodec<In, Out> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let serialized = bincode::serialize(item.borrow())?;
+        let len = u32::try_from(serialized.len())
+            .map_err(|_| CodecError::FrameTooLarge { len: serialized.len(), max: u32::MAX as usize })?;
+        dst.reserve(LENGTH_PREFIX + serialized.len());
+        dst.put_u32(len);
+        dst.put_slice(&serialized);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{Cursor, Read};
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Serialize, Deserialize};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
+use frame_codec::{BlockingFramed, CodecError, FrameCodec};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Message {
+    id: u32,
+    body: String,
+}
+
+fn message(id: u32) -> Message {
+    Message { id, body: format!("message number {}", id) }
+}
+
+type Codec = FrameCodec<Message, Message>;
+
+fn encode(message: Message) -> BytesMut {
+    let mut buf = BytesMut::new();
+    Codec::new().encode(message, &mut buf).unwrap();
+    buf
+}
+
+// Hands out at most `chunk` bytes per read, like a slow socket.
+struct Trickle<R> {
+    inner: R,
+    chunk: usize,
+}
+
+impl<R: Read> Read for Trickle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.chunk);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+#[test]
+fn frame_matches_bincode_serialize_with_length_prefix() {
+    let buf = encode(message(1));
+    let body = bincode::serialize(&message(1)).unwrap();
+    assert_eq!(&buf[..4], &(body.len() as u32).to_be_bytes());
+    assert_eq!(&buf[4..], &body[..]);
+}
+
+#[test]
+fn partial_frame_waits_for_more_bytes() {
+    let full = encode(message(7));
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+
+    for byte in &full[..full.len() - 1] {
+        buf.extend_from_slice(&[*byte]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+    buf.extend_from_slice(&full[full.len() - 1..]);
+    let decoded = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+    assert_eq!(decoded, message(7));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn several_frames_in_one_buffer_decode_one_by_one() {
+    let mut buf = encode(message(1));
+    buf.extend_from_slice(&encode(message(2)));
+    let second = encode(message(3));
+    // Only the start of the third frame has arrived
+    buf.extend_from_slice(&second[..6]);
+
+    let mut codec = Codec::new();
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap(), message(1));
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap(), message(2));
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    buf.extend_from_slice(&second[6..]);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap(), message(3));
+}
+
+#[test]
+fn oversized_frame_is_rejected_from_its_prefix() {
+    let mut codec = Codec::with_max_frame_size(16);
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&u32::MAX.to_be_bytes());
+
+    match codec.decode(&mut buf) {
+        Err(CodecError::FrameTooLarge { len, max }) => {
+            assert_eq!(len, u32::MAX as usize);
+            assert_eq!(max, 16);
+        },
+        other => panic!("expected FrameTooLarge, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn undecodable_frame_is_consumed_and_reported() {
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&3u32.to_be_bytes());
+    buf.extend_from_slice(&[0xff, 0xff, 0xff]);
+    buf.extend_from_slice(&encode(message(4)));
+
+    let mut codec = Codec::new();
+    assert!(codec.decode(&mut buf).unwrap().unwrap().is_err());
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap(), message(4));
+}
+
+#[test]
+fn inner_length_is_bounded_by_the_frame_limit() {
+    // A string claiming u64::MAX bytes inside a small frame
+    let mut body = 1u32.to_le_bytes().to_vec();
+    body.extend_from_slice(&u64::MAX.to_le_bytes());
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+
+    let mut codec = Codec::with_max_frame_size(64);
+    assert!(codec.decode(&mut buf).unwrap().unwrap().is_err());
+}
+
+#[test]
+fn blocking_reads_frames_split_across_reads() {
+    let mut bytes = encode(message(1));
+    bytes.extend_from_slice(&encode(message(2)));
+    let stream = Trickle { inner: Cursor::new(bytes.to_vec()), chunk: 3 };
+
+    let mut framed = BlockingFramed::new(stream, Codec::new());
+    assert_eq!(framed.recv().unwrap().unwrap().unwrap(), message(1));
+    assert_eq!(framed.recv().unwrap().unwrap().unwrap(), message(2));
+    assert!(framed.recv().unwrap().is_none());
+}
+
+#[test]
+fn blocking_truncated_frame_is_an_error() {
+    let bytes = encode(message(1));
+    let stream = Cursor::new(bytes[..bytes.len() - 2].to_vec());
+
+    let mut framed = BlockingFramed::new(stream, Codec::new());
+    assert!(matches!(framed.recv(), Err(CodecError::Io(_))));
+}
+
+#[test]
+fn blocking_send_writes_one_frame() {
+    let mut framed = BlockingFramed::new(Vec::new(), Codec::new());
+    framed.send(message(5)).unwrap();
+    framed.send(message(6)).unwrap();
+
+    let mut expected = encode(message(5));
+    expected.extend_from_slice(&encode(message(6)));
+    assert_eq!(framed.into_inner(), expected.to_vec());
+}
+
+#[tokio::test]
+async fn async_frames_split_across_writes() {
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut framed = FramedRead::new(server, Codec::new());
+
+    let mut bytes = encode(message(1));
+    bytes.extend_from_slice(&encode(message(2)));
+    tokio::spawn(async move {
+        for chunk in bytes.chunks(5) {
+            client.write_all(chunk).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+    });
+
+    assert_eq!(framed.next().await.unwrap().unwrap().unwrap(), message(1));
+    assert_eq!(framed.next().await.unwrap().unwrap().unwrap(), message(2));
+    assert!(framed.next().await.is_none());
+}
+
+#[tokio::test]
+async fn async_round_trip() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Framed::new(client, Codec::new());
+    let mut server = Framed::new(server, Codec::new());
+
+    client.send(message(9)).await.unwrap();
+    let received = server.next().await.unwrap().unwrap().unwrap();
+    server.send(received).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap().unwrap(), message(9));
+}
+
+#[tokio::test]
+async fn async_oversized_frame_is_an_error() {
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut framed = FramedRead::new(server, Codec::with_max_frame_size(16));
+
+    client.write_all(&1024u32.to_be_bytes()).await.unwrap();
+    assert!(matches!(framed.next().await, Some(Err(CodecError::FrameTooLarge { .. }))));
+}
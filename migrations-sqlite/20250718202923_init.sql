@@ -0,0 +1,8 @@
+-- SQLite has no enum types, so `priority` is a text column restricted to the Postgres enum labels.
+CREATE TABLE IF NOT EXISTS tasks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    priority TEXT NOT NULL CHECK (priority IN ('Low', 'Regular', 'Urgent')),
+    completed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
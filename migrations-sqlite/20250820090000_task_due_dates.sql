@@ -0,0 +1,4 @@
+ALTER TABLE tasks ADD COLUMN due_at TIMESTAMP;
+
+-- Only open tasks are ever looked up by due date.
+CREATE INDEX tasks_open_due_at_idx ON tasks (due_at) WHERE NOT completed AND archived_at IS NULL AND due_at IS NOT NULL;
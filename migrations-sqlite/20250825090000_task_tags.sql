@@ -0,0 +1,8 @@
+CREATE TABLE task_tags (
+    task_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL CHECK (tag <> ''),
+    PRIMARY KEY (task_id, tag)
+);
+
+-- The primary key serves the tags of a task, this one the tasks with a tag.
+CREATE INDEX task_tags_tag_idx ON task_tags (tag, task_id);
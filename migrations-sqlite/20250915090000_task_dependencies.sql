@@ -0,0 +1,10 @@
+-- `blocked_id` can't be done before `blocker_id` is.
+CREATE TABLE task_dependencies (
+    blocker_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+    blocked_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+    PRIMARY KEY (blocked_id, blocker_id),
+    CHECK (blocker_id <> blocked_id)
+);
+
+-- The primary key serves the blockers of a task, this one the tasks a task blocks.
+CREATE INDEX task_dependencies_blocker_id_idx ON task_dependencies (blocker_id, blocked_id);
@@ -0,0 +1,4 @@
+ALTER TABLE tasks ADD COLUMN completed_at TIMESTAMP;
+
+-- The real completion time of existing tasks is unknown, so they're stamped with the migration time.
+UPDATE tasks SET completed_at = CURRENT_TIMESTAMP WHERE completed;
@@ -1,20 +1,44 @@
 use std::collections::BTreeMap;
 use std::net::TcpStream;
 use thiserror::Error as ThisError;
-use mongodb_net::{ServerResponse, Task, Priority, ClientRequest, Command, CommandResponse, CommandResponseValue};
-use std::io::{Write, Read, stdin};
+use mongodb_net::{ServerResponse, Task, Project, Priority, ClientRequest, Command, CommandResult, CommandResponseValue, CommandError, ErrorKind, Hello, HelloResponse, TagMatch};
+use std::io::stdin;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::thread::sleep;
+use std::time::Duration;
+use frame_codec::{BlockingFramed, CodecError, FrameCodec};
+use uuid::Uuid;
 
 #[derive(ThisError, Debug)]
 enum Error {
 
     #[error("I/O Error: {0}")]
-    IOError(#[from] std::io::Error),
+    Io(#[from] std::io::Error),
 
     #[error("Parse Int Error: {0}")]
-    ParseIntError(#[from] std::num::ParseIntError),
+    ParseInt(#[from] std::num::ParseIntError),
+
+    #[error("Date Parse Error: {0}")]
+    ParseDate(#[from] chrono::ParseError),
 
     #[error("Serialization error: {0}")]
-    SerializationError(#[from] bincode::Error),
+    Serialization(#[from] bincode::Error),
+
+    #[error("Framing error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error("Connection lost")]
+    ConnectionClosed,
+
+    // The server turned the request away without running it and hangs up next.
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Server refused the connection: {0}")]
+    Handshake(String),
+
+    #[error("Request not sent again, check whether it went through before repeating it")]
+    NotRetried,
 
     #[error("{0}")]
     Custom(String),
@@ -23,7 +47,8 @@ enum Error {
 
 #[derive(Clone)]
 struct TaskLocalStore {
-    tasks: BTreeMap<String, Task>
+    tasks: BTreeMap<String, Task>,
+    projects: BTreeMap<String, Project>,
 }
 
 impl TaskLocalStore {
@@ -32,6 +57,7 @@ impl TaskLocalStore {
         let tasks: BTreeMap<String, Task> = BTreeMap::new();
         Self {
             tasks,
+            projects: BTreeMap::new(),
         }
     }
 
@@ -39,6 +65,34 @@ impl TaskLocalStore {
         self.tasks.insert(task.get_id(), task);
     }
 
+    // The server deletes the subtasks along with the task, so they go from here too.
+    fn remove(&mut self, id: &str) {
+        self.tasks.remove(id);
+        let subtasks: Vec<String> = self.tasks.values()
+            .filter(|task| task.get_parent_id() == Some(id))
+            .map(Task::get_id)
+            .collect();
+        for subtask in subtasks {
+            self.remove(&subtask);
+        }
+    }
+
+    fn upsert_project(&mut self, project: Project) {
+        self.projects.insert(project.id.clone(), project);
+    }
+
+    fn remove_project(&mut self, id: &str) {
+        self.projects.remove(id);
+    }
+
+    // Tasks not fetched yet are shown by id.
+    fn title(&self, id: &str) -> String {
+        match self.tasks.get(id) {
+            Some(task) => task.get_title(),
+            None => id.to_string(),
+        }
+    }
+
     fn select_id(&self) -> Result<String, Error> {
         if self.tasks.is_empty() {
             return Err(Error::Custom("The local store is empty, try fetching some values".to_string()));
@@ -55,9 +109,58 @@ impl TaskLocalStore {
         stdin().read_line(&mut selected)?;
         let selected: u8 = selected.trim().parse::<u8>()? - 1;
         if let Some((_id, task)) = self.tasks.iter().nth(selected as usize) {
-            return Ok(task.get_id());
+            Ok(task.get_id())
         } else {
-            return Err(Error::Custom("Selected task isn't valid.".to_string()));
+            Err(Error::Custom("Selected task isn't valid.".to_string()))
+        }
+    }
+
+    // An empty answer picks no parent, making the task a top-level one.
+    fn select_parent_id(&self) -> Result<Option<String>, Error> {
+        println!("== fetched tasks list ==");
+        for (i, task) in self.tasks.values().enumerate() {
+            println!("{}. {}", i + 1, task.get_title());
+        }
+        println!("Select the parent task (1/{}, empty for none):", self.tasks.len());
+        let mut selected = String::new();
+        stdin().read_line(&mut selected)?;
+        if selected.trim().is_empty() {
+            return Ok(None);
+        }
+        let selected: usize = selected.trim().parse()?;
+        match self.tasks.values().nth(selected.wrapping_sub(1)) {
+            Some(task) => Ok(Some(task.get_id())),
+            None => Err(Error::Custom("Selected task isn't valid.".to_string())),
+        }
+    }
+
+    // With `none_allowed` an empty answer, or having no projects fetched, picks no project.
+    fn select_project_id(&self, none_allowed: bool) -> Result<Option<String>, Error> {
+        if self.projects.is_empty() {
+            if none_allowed {
+                return Ok(None);
+            }
+            return Err(Error::Custom("No projects fetched yet, try printing the projects".to_string()));
+        }
+
+        println!("== fetched projects list ==");
+        for (i, project) in self.projects.values().enumerate() {
+            println!("{}. {}", i + 1, project.name);
+        }
+        if none_allowed {
+            println!("Select a project (1/{}, empty for none):", self.projects.len());
+        } else {
+            println!("Select a project (1/{}):", self.projects.len());
+        }
+        let mut selected = String::new();
+        stdin().read_line(&mut selected)?;
+        if none_allowed && selected.trim().is_empty() {
+            return Ok(None);
+        }
+        let selected: usize = selected.trim().parse()?;
+        match self.projects.values().nth(selected.wrapping_sub(1)) {
+            Some(project) => Ok(Some(project.id.clone())),
+            None => Err(Error::Custom("Selected project isn't valid.".to_string())),
         }
     }
 
@@ -71,69 +174,340 @@ fn menu() -> Result<u8, Error> {
     println!("5. Mark a task as completed");
     println!("6. Edit a task title");
     println!("7. Edit a task priority");
-    println!("Choose an option (1/7): ");
+    println!("8. Delete a task");
+    println!("9. Archive a task");
+    println!("10. Unarchive a task");
+    println!("11. Print archived tasks");
+    println!("12. Reopen a completed task");
+    println!("13. Check the server's health");
+    println!("14. Set a task due date");
+    println!("15. Print overdue tasks");
+    println!("16. Print tasks due in the next days");
+    println!("17. Add a tag to a task");
+    println!("18. Remove a tag from a task");
+    println!("19. Print tasks by tag");
+    println!("20. Create a project");
+    println!("21. Rename a project");
+    println!("22. Delete a project");
+    println!("23. Print projects");
+    println!("24. Move a task to another project");
+    println!("25. Print a project's pending tasks");
+    println!("26. Print a project's completed tasks");
+    println!("27. Add a subtask");
+    println!("28. Move a subtask to another parent");
+    println!("29. Print a task with its subtasks");
+    println!("30. Make a task wait on another one");
+    println!("31. Stop a task from waiting on another one");
+    println!("32. Print blocked tasks");
+    println!("33. Print tasks ready to work on");
+    println!("Choose an option (1/33): ");
     let mut buffer = String::new();
     stdin().read_line(&mut buffer)?;
     let result: u8 = buffer.trim().parse()?;
     Ok(result)
 }
 
-fn request_to_server(stream: &mut TcpStream, rq: ClientRequest) -> Result<ServerResponse, Error> {
-    let rq_bytes = bincode::serialize(&rq)?;
-    let rq_len = rq_bytes.len() as u32;
-    stream.write_all(&rq_len.to_be_bytes())?;
-    stream.write_all(&rq_bytes[..])?;
-    let mut rs_len_buf = [0u8; 4];
-    stream.read_exact(&mut rs_len_buf).expect("Connection lost. Shutting down.");
-    let rs_len = u32::from_be_bytes(rs_len_buf) as usize;
-    let mut rs_buf = vec![0u8; rs_len];
-    stream.read_exact(&mut rs_buf).expect("Connection lost. Shutting down.");
-    let rs: ServerResponse = bincode::deserialize(&rs_buf)?;
+// Empty input means no due date. A bare date is due at the end of that day; like every time the
+// server keeps, it is taken as UTC.
+fn read_due_date() -> Result<Option<NaiveDateTime>, Error> {
+    println!("Enter the due date (YYYY-MM-DD or YYYY-MM-DD HH:MM, empty for none):");
+    let mut buffer = String::new();
+    stdin().read_line(&mut buffer)?;
+    let input = buffer.trim();
+    if input.is_empty() {
+        return Ok(None);
+    }
+    match NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M") {
+        Ok(due_at) => Ok(Some(due_at)),
+        Err(_) => Ok(NaiveDate::parse_from_str(input, "%Y-%m-%d")?.and_hms_opt(23, 59, 59)),
+    }
+}
+
+const SERVER_ADDR: &str = "127.0.0.1:8992";
+
+// Responses can be far bigger than requests, e.g. every task in the database.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+// A lost connection is reopened up to RECONNECT_ATTEMPTS times, waiting RECONNECT_DELAY after the
+// first failure and twice as long after every further one.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+type Connection = BlockingFramed<TcpStream, FrameCodec<ServerResponse, ClientRequest>>;
+
+// Has to be the first exchange on a new connection, the server drops clients it can't talk to.
+fn handshake(stream: TcpStream) -> Result<(Connection, Hello), Error> {
+    let codec = FrameCodec::<HelloResponse, Hello>::with_max_frame_size(MAX_RESPONSE_SIZE);
+    let mut framed = BlockingFramed::new(stream, codec);
+    framed.send(Hello::new())?;
+    let server = match framed.recv()?.ok_or(Error::ConnectionClosed)?? {
+        HelloResponse::Accepted(server) => server,
+        HelloResponse::Rejected { reason, .. } => return Err(Error::Handshake(reason)),
+    };
+    let framed = framed.map_codec(|codec| FrameCodec::with_max_frame_size(codec.max_frame_size()));
+    Ok((framed, server))
+}
+
+fn connect() -> Result<(Connection, Hello), Error> {
+    handshake(TcpStream::connect(SERVER_ADDR)?)
+}
+
+fn reconnect() -> Result<Connection, Error> {
+    let mut delay = RECONNECT_DELAY;
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok((stream, _server)) => {
+                println!("Reconnected");
+                return Ok(stream);
+            },
+            Err(Error::Io(e)) if attempt < RECONNECT_ATTEMPTS => {
+                println!("Couldn't reconnect ({}), trying again in {:?}", e, delay);
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn exchange(stream: &mut Connection, rq: &ClientRequest) -> Result<ServerResponse, Error> {
+    stream.send(rq)?;
+    let rs = stream.recv()?.ok_or(Error::ConnectionClosed)??;
+    if rs.error().is_some_and(|e| e.kind == ErrorKind::ShuttingDown) {
+        return Err(Error::ShuttingDown);
+    }
     Ok(rs)
 }
 
-fn handle_response(store: &mut TaskLocalStore, rs: ServerResponse) -> Result<(), Error> {
-    let cmd_responses = rs.unwrap();
+fn confirm(question: &str) -> Result<bool, Error> {
+    println!("{} (y/n): ", question);
+    let mut buffer = String::new();
+    stdin().read_line(&mut buffer)?;
+    Ok(matches!(buffer.trim(), "y" | "Y" | "yes"))
+}
+
+// Reconnects when the connection was lost. Requests the server turned away while shutting down and
+// read-only ones are sent again right away, for anything else the server may already have run it,
+// so the user decides. The idempotency key makes
+// a confirmed retry safe as long as the server still has the saved response, which it doesn't
+// after a restart of the in-memory backend or when saving it failed.
+fn request_to_server(stream: &mut Connection, rq: &ClientRequest) -> Result<ServerResponse, Error> {
+    let rq = if rq.is_read_only() {
+        rq.clone()
+    } else {
+        rq.clone().with_idempotency_key(&Uuid::new_v4().to_string())
+    };
+    match exchange(stream, &rq) {
+        // Nothing ran, so sending it again can't run it twice
+        Err(Error::ShuttingDown) => {
+            println!("Server is shutting down, reconnecting...");
+            *stream = reconnect()?;
+            exchange(stream, &rq)
+        },
+        Err(Error::Io(_) | Error::Codec(_) | Error::ConnectionClosed) => {
+            println!("Connection lost, reconnecting...");
+            *stream = reconnect()?;
+            if rq.is_read_only() || confirm("The server may or may not have run the request. Send it again?")? {
+                exchange(stream, &rq)
+            } else {
+                Err(Error::NotRetried)
+            }
+        },
+        result => result,
+    }
+}
 
-    for cmd in cmd_responses {
-        match cmd {
-            CommandResponse::Success(cmd_val) => {
+fn print_error(e: &CommandError) {
+    match e.kind {
+        ErrorKind::NotFound => println!("Not found: {}", e.message),
+        ErrorKind::InvalidId => println!("That doesn't look like a task id: {}", e.message),
+        ErrorKind::ValidationFailed => println!("Request rejected: {}", e.message),
+        ErrorKind::Conflict => println!("Conflicts with existing data: {}", e.message),
+        ErrorKind::Unauthorized => println!("Not allowed: {}", e.message),
+        ErrorKind::Unavailable => println!("Server unavailable, try again later: {}", e.message),
+        ErrorKind::MalformedRequest => println!("The server couldn't read the request: {}", e.message),
+        ErrorKind::Internal => println!("Server-side error: {}", e.message),
+        ErrorKind::ShuttingDown => println!("Server is shutting down: {}", e.message),
+    }
+    if let Some(details) = &e.details {
+        println!("  {}", details);
+    }
+}
+
+fn handle_response(store: &mut TaskLocalStore, rq: &ClientRequest, rs: ServerResponse) -> Result<(), Error> {
+    // The server refused the whole request, none of the commands ran
+    if let Some(e) = rs.error() {
+        print_error(e);
+        return Ok(());
+    }
+    // Responses are matched to the commands we sent by their index, not by arrival order
+    for index in 0..rq.get_commands().len() {
+        match rs.get(index) {
+            Some(CommandResult::Success(cmd_val)) => {
                 match cmd_val {
                     CommandResponseValue::NewTask(task) => {
-                        store.upsert(task);
+                        store.upsert(task.clone());
                         eprintln!("Succesfully created task.");
                     },
                     CommandResponseValue::PendingTasks(tasks) => {
                         for task in tasks {
                             println!("{}", task.format());
-                            store.upsert(task);
+                            store.upsert(task.clone());
                         }
                     },
                     CommandResponseValue::DoneTasks(tasks) => {
                         for task in tasks {
                             println!("{}", task.format());
-                            store.upsert(task);
+                            store.upsert(task.clone());
                         }
                     },
                     CommandResponseValue::MarkTaskDone(task) => {
-                        store.upsert(task);
+                        store.upsert(task.clone());
                         println!("Successfully marked task as done");
                     },
                     CommandResponseValue::EditTaskTitle(task) => {
                         println!("Succesfully changed title");
-                        store.upsert(task);
+                        store.upsert(task.clone());
                     },
                     CommandResponseValue::EditTaskPriority(task) => {
                         println!("Successfully changed priority");
-                        store.upsert(task);
+                        store.upsert(task.clone());
                     },
                     CommandResponseValue::QueryTaskById(task) => {
                         println!("{}", task.format());
-                        store.upsert(task);
+                        store.upsert(task.clone());
+                    },
+                    CommandResponseValue::DeleteTask(task) => {
+                        store.remove(&task.get_id());
+                        println!("Successfully deleted task: {}", task.format());
+                    },
+                    CommandResponseValue::ArchiveTask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully archived task");
+                    },
+                    CommandResponseValue::UnarchiveTask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully unarchived task");
+                    },
+                    CommandResponseValue::ReopenTask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully reopened task");
+                    },
+                    CommandResponseValue::ArchivedTasks(tasks) => {
+                        for task in tasks {
+                            println!("{}", task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::SetDueDate(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully set due date");
+                    },
+                    CommandResponseValue::OverdueTasks(tasks) | CommandResponseValue::DueWithin(tasks) => {
+                        if tasks.is_empty() {
+                            println!("No tasks");
+                        }
+                        for task in tasks {
+                            println!("{}", task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::AddTag(task) | CommandResponseValue::RemoveTag(task) => {
+                        store.upsert(task.clone());
+                        println!("{}", task.format());
+                    },
+                    CommandResponseValue::TasksWithTag(tasks) => {
+                        if tasks.is_empty() {
+                            println!("No tasks");
+                        }
+                        for task in tasks {
+                            println!("{}", task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::NewProject(project) => {
+                        store.upsert_project(project.clone());
+                        println!("Successfully created project {}", project.name);
+                    },
+                    CommandResponseValue::RenameProject(project) => {
+                        store.upsert_project(project.clone());
+                        println!("Successfully renamed project to {}", project.name);
+                    },
+                    CommandResponseValue::DeleteProject(project) => {
+                        store.remove_project(&project.id);
+                        println!("Successfully deleted project {}", project.name);
+                    },
+                    CommandResponseValue::Projects(projects) => {
+                        if projects.is_empty() {
+                            println!("No projects");
+                        }
+                        for project in projects {
+                            println!("{}", project.name);
+                            store.upsert_project(project.clone());
+                        }
+                    },
+                    CommandResponseValue::MoveTask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully moved task");
+                    },
+                    CommandResponseValue::ProjectPendingTasks(tasks) | CommandResponseValue::ProjectDoneTasks(tasks) => {
+                        if tasks.is_empty() {
+                            println!("No tasks");
+                        }
+                        for task in tasks {
+                            println!("{}", task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::AddSubtask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully added subtask");
+                    },
+                    CommandResponseValue::MoveSubtask(task) => {
+                        store.upsert(task.clone());
+                        println!("Successfully moved subtask");
+                    },
+                    CommandResponseValue::TaskTree(tree) => {
+                        // Every level of subtasks is indented a bit further
+                        for (depth, task) in tree.flatten() {
+                            println!("{}{}", "  ".repeat(depth), task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::AddDependency(task) | CommandResponseValue::RemoveDependency(task) => {
+                        store.upsert(task.clone());
+                        println!("{}", task.format());
+                        for blocker in task.get_blocked_by() {
+                            println!("  waits on {}", store.title(blocker));
+                        }
+                    },
+                    CommandResponseValue::BlockedTasks(tasks) | CommandResponseValue::ReadyTasks(tasks) => {
+                        if tasks.is_empty() {
+                            println!("No tasks");
+                        }
+                        for task in tasks {
+                            println!("{}", task.format());
+                            store.upsert(task.clone());
+                        }
+                    },
+                    CommandResponseValue::Pong => println!("The server is alive"),
+                    CommandResponseValue::Health(report) => {
+                        print!("{}", report);
+                        if report.is_ready() {
+                            println!("The server is ready");
+                        } else {
+                            println!("The server is not ready");
+                        }
                     },
                 }
             },
-            CommandResponse::Error(e) => println!("Server-side error: {}",e),
+            Some(CommandResult::Error(e)) => print_error(e),
+            Some(CommandResult::Aborted{failed_index}) => {
+                println!("Rolled back, command #{} of the batch failed", failed_index + 1);
+            },
+            None => println!("The server didn't answer command #{}", index + 1),
         }
     }
     Ok(())
@@ -141,9 +515,9 @@ fn handle_response(store: &mut TaskLocalStore, rs: ServerResponse) -> Result<(),
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let mut stream = TcpStream::connect("127.0.0.1:8992").expect("Failed to connect to server. Panicking.");
+    let (mut stream, server) = connect()?;
     let mut store = TaskLocalStore::new();
-    println!("Connection successful");
+    println!("Connection successful (protocol version {})", server.version);
     println!("=== Tasks App ===");
 
     loop {
@@ -152,14 +526,14 @@ async fn main() -> Result<(), Error> {
             Ok(n) => match n {
                 1 => {
                     let rq = ClientRequest::new(&[Command::PendingTasks]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -167,14 +541,14 @@ async fn main() -> Result<(), Error> {
                 },
                 2 => {
                     let rq = ClientRequest::new(&[Command::DoneTasks]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -191,14 +565,14 @@ async fn main() -> Result<(), Error> {
                     };
                         
                     let rq = ClientRequest::new(&[Command::QueryTaskById(id)]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -227,25 +601,38 @@ async fn main() -> Result<(), Error> {
                             continue;
                         },
                     };
-                    let priority_val: Priority;
-                    match priority {
-                        1 => priority_val = Priority::Low,
-                        2 => priority_val = Priority::Regular,
-                        3 => priority_val = Priority::Urgent,
+                    let priority_val = match priority {
+                        1 => Priority::Low,
+                        2 => Priority::Regular,
+                        3 => Priority::Urgent,
                         _ => {
                             println!("You selected an invalid number, sucker! Try again.");
                             continue;
                         }
-                    }
-                    let rq = ClientRequest::new(&[Command::NewTask{title, priority: priority_val}]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    };
+                    let due_at = match read_due_date() {
+                        Ok(due_at) => due_at,
+                        Err(e) => {
+                            eprintln!("Error reading the due date: {}. Try again.", e);
+                            continue;
+                        },
+                    };
+                    let project_id = match store.select_project_id(true) {
+                        Ok(project_id) => project_id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+                    let rq = ClientRequest::new(&[Command::NewTask{title, priority: priority_val, due_at, project_id}]);
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -263,14 +650,14 @@ async fn main() -> Result<(), Error> {
                     };
                         
                     let rq = ClientRequest::new(&[Command::MarkTaskDone(id)]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -296,14 +683,14 @@ async fn main() -> Result<(), Error> {
                     let title = title.trim().to_string();
                         
                     let rq = ClientRequest::new(&[Command::EditTaskTitle{task_id: id, new_title: title}]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
@@ -332,26 +719,485 @@ async fn main() -> Result<(), Error> {
                             continue;
                         },
                     };
-                    let priority_val: Priority;
-                    match priority {
-                        1 => priority_val = Priority::Low,
-                        2 => priority_val = Priority::Regular,
-                        3 => priority_val = Priority::Urgent,
+                    let priority_val = match priority {
+                        1 => Priority::Low,
+                        2 => Priority::Regular,
+                        3 => Priority::Urgent,
                         _ => {
                             println!("You selected an invalid number, sucker! Try again.");
                             continue;
                         }
-                    }
+                    };
                         
                     let rq = ClientRequest::new(&[Command::EditTaskPriority{task_id: id, priority: priority_val}]);
-                    let response = match request_to_server(&mut stream, rq) {
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                8..=10 | 12 => {
+                    // delete, archive, unarchive or reopen a task
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let command = match n {
+                        8 => Command::DeleteTask(id),
+                        9 => Command::ArchiveTask(id),
+                        10 => Command::UnarchiveTask(id),
+                        _ => Command::ReopenTask(id),
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                11 => {
+                    let rq = ClientRequest::new(&[Command::ArchivedTasks]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                13 => {
+                    let rq = ClientRequest::new(&[Command::Health]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                14 => {
+                    // set or clear the due date
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let due_at = match read_due_date() {
+                        Ok(due_at) => due_at,
+                        Err(e) => {
+                            eprintln!("Error reading the due date: {}. Try again.", e);
+                            continue;
+                        },
+                    };
+                    let rq = ClientRequest::new(&[Command::SetDueDate{task_id: id, due_at}]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                15 => {
+                    let rq = ClientRequest::new(&[Command::OverdueTasks]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                16 => {
+                    let mut days = String::new();
+                    println!("How many days ahead?");
+                    if let Err(e) = stdin().read_line(&mut days) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let days: u32 = match days.trim().parse() {
+                        Ok(days) => days,
+                        Err(e) => {
+                            eprintln!("Error parsing input: {}. Try again.", e);
+                            continue;
+                        },
+                    };
+                    let rq = ClientRequest::new(&[Command::DueWithin{days}]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                17 | 18 => {
+                    // add or remove a tag
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let mut tag = String::new();
+                    println!("Enter the tag:");
+                    if let Err(e) = stdin().read_line(&mut tag) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let tag = tag.trim().to_string();
+
+                    let command = match n {
+                        17 => Command::AddTag{task_id: id, tag},
+                        _ => Command::RemoveTag{task_id: id, tag},
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                19 => {
+                    let mut tags = String::new();
+                    println!("Enter the tags, separated by spaces:");
+                    if let Err(e) = stdin().read_line(&mut tags) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let tags: Vec<String> = tags.split_whitespace().map(str::to_string).collect();
+
+                    let mut matching = String::new();
+                    println!("Tasks with all of them or any of them? (1: All, 2: Any)");
+                    if let Err(e) = stdin().read_line(&mut matching) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let matching = match matching.trim() {
+                        "1" => TagMatch::All,
+                        "2" => TagMatch::Any,
+                        _ => {
+                            println!("You selected an invalid number. Try again.");
+                            continue;
+                        },
+                    };
+                    let rq = ClientRequest::new(&[Command::TasksWithTag{tags, matching}]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                20 | 21 => {
+                    // create or rename a project
+                    let project_id = if n == 21 {
+                        match store.select_project_id(false) {
+                            Ok(project_id) => project_id,
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                continue;
+                            },
+                        }
+                    } else {
+                        None
+                    };
+
+                    let mut name = String::new();
+                    println!("Enter the project name:");
+                    if let Err(e) = stdin().read_line(&mut name) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let name = name.trim().to_string();
+
+                    let command = match project_id {
+                        Some(project_id) => Command::RenameProject{project_id, name},
+                        None => Command::NewProject{name},
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                22 | 25 | 26 => {
+                    // delete a project or print its tasks
+                    let project_id = match store.select_project_id(false) {
+                        Ok(Some(project_id)) => project_id,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let command = match n {
+                        22 => Command::DeleteProject(project_id),
+                        25 => Command::ProjectPendingTasks(project_id),
+                        _ => Command::ProjectDoneTasks(project_id),
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                23 => {
+                    let rq = ClientRequest::new(&[Command::Projects]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                24 => {
+                    // move a task to a project, or out of any
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let project_id = match store.select_project_id(true) {
+                        Ok(project_id) => project_id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+                    let rq = ClientRequest::new(&[Command::MoveTask{task_id: id, project_id}]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                27 => {
+                    // add a subtask
+                    println!("Choose the parent task.");
+                    let parent_id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let mut title = String::new();
+                    println!("Enter the subtask title:");
+                    if let Err(e) = stdin().read_line(&mut title) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let title = title.trim().to_string();
+
+                    let mut priority = String::new();
+                    println!("Introduce the task priority (1: Low, 2: Regular, 3: Urgent):");
+                    if let Err(e) = stdin().read_line(&mut priority) {
+                        eprintln!("Error reading line: {}. Try again.", e);
+                        continue;
+                    };
+                    let priority = match priority.trim() {
+                        "1" => Priority::Low,
+                        "2" => Priority::Regular,
+                        "3" => Priority::Urgent,
+                        _ => {
+                            println!("You selected an invalid number. Try again.");
+                            continue;
+                        }
+                    };
+                    let rq = ClientRequest::new(&[Command::AddSubtask{parent_id, title, priority}]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                28 | 29 => {
+                    // move a subtask, or print a task with its subtasks
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let command = if n == 28 {
+                        match store.select_parent_id() {
+                            Ok(parent_id) => Command::MoveSubtask{task_id: id, parent_id},
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                continue;
+                            },
+                        }
+                    } else {
+                        Command::TaskTree(id)
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                30 | 31 => {
+                    // add or remove a dependency
+                    println!("Choose the task that has to wait.");
+                    let id = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+                    println!("Choose the task it waits on.");
+                    let blocked_by = match store.select_id() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        },
+                    };
+
+                    let command = if n == 30 {
+                        Command::AddDependency{task_id: id, blocked_by}
+                    } else {
+                        Command::RemoveDependency{task_id: id, blocked_by}
+                    };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
+                        Ok(rq) => rq,
+                        Err(e) => {
+                            eprintln!("Error: {}. Try again", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
+                        eprintln!("Error handling the response: {}, try again.", e);
+                        continue;
+                    };
+
+                },
+                32 | 33 => {
+                    let command = if n == 32 { Command::BlockedTasks } else { Command::ReadyTasks };
+                    let rq = ClientRequest::new(&[command]);
+                    let response = match request_to_server(&mut stream, &rq) {
                         Ok(rq) => rq,
                         Err(e) => {
                             eprintln!("Error: {}. Try again", e);
                             continue;
                         }
                     };
-                    if let Err(e) = handle_response(&mut store, response) {
+                    if let Err(e) = handle_response(&mut store, &rq, response) {
                         eprintln!("Error handling the response: {}, try again.", e);
                         continue;
                     };
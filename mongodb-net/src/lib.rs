@@ -1,11 +1,12 @@
 use serde::{Serialize, Deserialize};
-use mongodb::bson::doc;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use mongodb::bson::oid::ObjectId;
 use mongodb::{bson};
 use std::str::FromStr;
 use std::fmt;
 
+pub use net::{Priority, CommandError, ErrorKind, HealthCheck, HealthReport, Hello, HelloResponse, TagMatch, relative_time};
+
 #[derive(Debug)]
 pub struct DateTimeOutOfRangeError;
 
@@ -17,24 +18,11 @@ impl fmt::Display for DateTimeOutOfRangeError {
 
 impl std::error::Error for DateTimeOutOfRangeError {}
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-#[repr(u8)]
-pub enum Priority {
-    Low,
-    Regular,
-    Urgent,
-}
-
-impl Priority {
-
-    pub fn to_string(&self) -> String {
-        match self {
-            Priority::Low => "Low".to_string(),
-            Priority::Regular => "Regular".to_string(),
-            Priority::Urgent => "Urgent".to_string(),
-        }
-    } 
-
+fn from_timestamp_millis(millis: i64) -> Result<NaiveDateTime, DateTimeOutOfRangeError> {
+    match DateTime::from_timestamp_millis(millis) {
+        Some(timestamp) => Ok(timestamp.naive_utc()),
+        None => Err(DateTimeOutOfRangeError),
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -44,7 +32,21 @@ pub struct TaskDocument {
     title: String,
     priority: Priority,
     completed: bool,
-    created_at: i64
+    created_at: i64,
+    #[serde(default)]
+    completed_at: Option<i64>,
+    #[serde(default)]
+    archived_at: Option<i64>,
+    #[serde(default)]
+    due_at: Option<i64>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    project_id: Option<ObjectId>,
+    #[serde(default)]
+    parent_id: Option<ObjectId>,
+    #[serde(default)]
+    blocked_by: Vec<ObjectId>,
 }
 
 impl TaskDocument {
@@ -56,16 +58,40 @@ impl TaskDocument {
             title: title.to_string(),
             priority,
             completed,
-            created_at
+            created_at,
+            completed_at: None,
+            archived_at: None,
+            due_at: None,
+            tags: Vec::new(),
+            project_id: None,
+            parent_id: None,
+            blocked_by: Vec::new(),
         })
     }
 
     pub fn as_task(&self) -> Result<Task, DateTimeOutOfRangeError> {
-        let timestamp = match NaiveDateTime::from_timestamp_millis(self.created_at) {
-            Some(timestamp) => timestamp,
-            None => return Err(DateTimeOutOfRangeError),
-        };
-        Ok(Task::new(&self.id.to_hex(), &self.title, self.priority, timestamp))
+        let completed_at = self.completed_at.map(from_timestamp_millis).transpose()?;
+        let archived_at = self.archived_at.map(from_timestamp_millis).transpose()?;
+        let due_at = self.due_at.map(from_timestamp_millis).transpose()?;
+        // `$addToSet` appends, tasks hand them out sorted
+        let mut tags = self.tags.clone();
+        tags.sort();
+        let mut blocked_by: Vec<String> = self.blocked_by.iter().map(|id| id.to_hex()).collect();
+        blocked_by.sort();
+        Ok(Task {
+            id: self.id.to_hex(),
+            title: self.title.clone(),
+            priority: self.priority,
+            completed: self.completed,
+            created_at: from_timestamp_millis(self.created_at)?,
+            completed_at,
+            archived_at,
+            due_at,
+            tags,
+            project_id: self.project_id.map(|project_id| project_id.to_hex()),
+            parent_id: self.parent_id.map(|parent_id| parent_id.to_hex()),
+            blocked_by,
+        })
     }
 
 }
@@ -76,7 +102,14 @@ pub struct Task {
     title: String,
     priority: Priority,
     completed: bool,
-    created_at: NaiveDateTime
+    created_at: NaiveDateTime,
+    completed_at: Option<NaiveDateTime>,
+    archived_at: Option<NaiveDateTime>,
+    due_at: Option<NaiveDateTime>,
+    tags: Vec<String>,
+    project_id: Option<String>,
+    parent_id: Option<String>,
+    blocked_by: Vec<String>,
 }
 
 impl Task {
@@ -87,10 +120,32 @@ impl Task {
             title: title.to_string(),
             priority,
             completed: false,
-            created_at
+            created_at,
+            completed_at: None,
+            archived_at: None,
+            due_at: None,
+            tags: Vec::new(),
+            project_id: None,
+            parent_id: None,
+            blocked_by: Vec::new(),
         }
     }
 
+    pub fn with_due_at(mut self, due_at: Option<NaiveDateTime>) -> Self {
+        self.due_at = due_at;
+        self
+    }
+
+    pub fn with_project_id(mut self, project_id: Option<String>) -> Self {
+        self.project_id = project_id;
+        self
+    }
+
+    pub fn with_parent_id(mut self, parent_id: Option<String>) -> Self {
+        self.parent_id = parent_id;
+        self
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -99,77 +154,93 @@ impl Task {
         self.title.to_string()
     }
 
-    pub fn format(&self) -> String {
-        format!("[{}]: {}", self.priority.to_string(), self.title)
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
     }
 
-    pub fn as_document(&self) -> Result<TaskDocument, bson::oid::Error> {
-        let doc = TaskDocument::new(&self.id, &self.title, self.priority, self.completed, self.created_at.timestamp_millis())?;
-        Ok(doc)
+    pub fn get_project_id(&self) -> Option<&str> {
+        self.project_id.as_deref()
     }
 
-}
-
-
-#[derive(Serialize, Deserialize, Clone)]
-pub enum Command {
-    NewTask{title: String, priority: Priority},
-    PendingTasks,
-    DoneTasks,
-    MarkTaskDone(String),
-    EditTaskTitle{task_id: String, new_title: String},
-    EditTaskPriority{task_id: String, priority: Priority},
-    QueryTaskById(String),
-}
-
-#[derive(Deserialize, Serialize)]
-pub struct ClientRequest {
-    commands: Vec<Command>,
-}
+    pub fn get_parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
 
-impl ClientRequest {
-    pub fn get_commands(&self) -> &[Command] {
-        &self.commands
+    pub fn get_blocked_by(&self) -> &[String] {
+        &self.blocked_by
     }
 
-    pub fn new(cmds: &[Command]) -> Self {
-        Self {
-            commands: cmds.to_vec(),
+    pub fn format(&self) -> String {
+        let mut line = format!("[{:?}]: {}", self.priority, self.title);
+        for tag in &self.tags {
+            line.push_str(" #");
+            line.push_str(tag);
         }
+        if !self.blocked_by.is_empty() {
+            line.push_str(&format!(" [blocked by {}]", self.blocked_by.join(", ")));
+        }
+        match (self.completed_at, self.due_at) {
+            (Some(completed_at), _) => line.push_str(&format!(" (done {})", completed_at.format("%Y-%m-%d %H:%M"))),
+            (None, Some(due_at)) => line.push_str(&format!(" (due {})", relative_time(due_at, Utc::now().naive_utc()))),
+            (None, None) => {},
+        }
+        line
     }
-}
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum CommandResponseValue {
-    NewTask(Task),
-    PendingTasks(Vec<Task>),
-    DoneTasks(Vec<Task>),
-    MarkTaskDone(Task),
-    EditTaskTitle(Task),
-    EditTaskPriority(Task),
-    QueryTaskById(Task),
-}
+    pub fn as_document(&self) -> Result<TaskDocument, bson::oid::Error> {
+        let doc = TaskDocument {
+            id: ObjectId::from_str(&self.id)?,
+            title: self.title.clone(),
+            priority: self.priority,
+            completed: self.completed,
+            created_at: self.created_at.and_utc().timestamp_millis(),
+            completed_at: self.completed_at.map(|completed_at| completed_at.and_utc().timestamp_millis()),
+            archived_at: self.archived_at.map(|archived_at| archived_at.and_utc().timestamp_millis()),
+            due_at: self.due_at.map(|due_at| due_at.and_utc().timestamp_millis()),
+            tags: self.tags.clone(),
+            project_id: self.project_id.as_deref().map(ObjectId::from_str).transpose()?,
+            parent_id: self.parent_id.as_deref().map(ObjectId::from_str).transpose()?,
+            blocked_by: self.blocked_by.iter().map(|id| ObjectId::from_str(id)).collect::<Result<_, _>>()?,
+        };
+        Ok(doc)
+    }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum CommandResponse {
-    Success(CommandResponseValue),
-    Error(String),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ServerResponse {
-    payload: Vec<CommandResponse>
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProjectDocument {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    name: String,
+    created_at: i64,
 }
 
-impl ServerResponse {
-    pub fn new(payload: &[CommandResponse]) -> Self  {
+impl ProjectDocument {
+
+    pub fn new(name: &str, created_at: i64) -> Self {
         Self {
-            payload: payload.to_vec(),
+            id: ObjectId::new(),
+            name: name.to_string(),
+            created_at,
         }
     }
 
-    pub fn unwrap(&self) -> Vec<CommandResponse> {
-        self.payload.clone()
+    pub fn as_project(&self) -> Result<Project, DateTimeOutOfRangeError> {
+        Ok(Project {
+            id: self.id.to_hex(),
+            name: self.name.clone(),
+            created_at: from_timestamp_millis(self.created_at)?,
+        })
     }
 
 }
+
+
+pub type Command = net::Command<String>;
+pub type ClientRequest = net::ClientRequest<String>;
+pub type Project = net::Project<String>;
+pub type TaskTree = net::TaskTree<Task>;
+pub type CommandResponseValue = net::CommandResponseValue<Task, String>;
+pub type CommandResult = net::CommandResult<Task, String>;
+pub type CommandResponse = net::CommandResponse<Task, String>;
+pub type ServerResponse = net::ServerResponse<Task, String>;
@@ -0,0 +1,39 @@
+use chrono::{NaiveDate, TimeDelta, Utc};
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use mongodb_net::{Task, TaskDocument};
+
+const BLOCKER: &str = "64b7f0a1c2d3e4f5a6b7c8d9";
+const OTHER_BLOCKER: &str = "64b7f0a1c2d3e4f5a6b7c8da";
+
+fn task(fields: Document) -> Task {
+    let created_at = NaiveDate::from_ymd_opt(2025, 8, 20).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let mut document = doc! {
+        "_id": ObjectId::new(),
+        "title": "task",
+        "priority": "Regular",
+        "completed": false,
+        "created_at": created_at.and_utc().timestamp_millis(),
+    };
+    document.extend(fields);
+    bson::from_document::<TaskDocument>(document).unwrap().as_task().unwrap()
+}
+
+#[test]
+fn tags_blockers_and_dates_follow_the_title() {
+    assert_eq!(task(doc! {}).format(), "[Regular]: task");
+
+    let tagged = task(doc! { "tags": ["urgent", "home"] });
+    assert_eq!(tagged.format(), "[Regular]: task #home #urgent");
+
+    let due_at = Utc::now() + TimeDelta::days(3);
+    let due = task(doc! { "due_at": due_at.timestamp_millis() });
+    assert_eq!(due.format(), "[Regular]: task (due in 3 days)");
+
+    let done_at = NaiveDate::from_ymd_opt(2025, 8, 21).unwrap().and_hms_opt(9, 30, 0).unwrap();
+    let done = task(doc! { "completed": true, "completed_at": done_at.and_utc().timestamp_millis() });
+    assert_eq!(done.format(), "[Regular]: task (done 2025-08-21 09:30)");
+
+    let blockers = [ObjectId::parse_str(OTHER_BLOCKER).unwrap(), ObjectId::parse_str(BLOCKER).unwrap()];
+    let blocked = task(doc! { "tags": ["home"], "blocked_by": blockers.to_vec() });
+    assert_eq!(blocked.format(), format!("[Regular]: task #home [blocked by {}, {}]", BLOCKER, OTHER_BLOCKER));
+}
@@ -1,8 +1,16 @@
 use futures_util::stream::TryStreamExt;
-use mongodb::{options::ReturnDocument, Client, Collection};
-use mongodb::bson::{oid::ObjectId, doc, to_bson};
-use chrono::Utc;
-use mongodb_net::{Task, Priority, TaskDocument, DateTimeOutOfRangeError};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::sync::Mutex;
+use mongodb::{options::{ClientOptions, IndexOptions, ReturnDocument, UpdateModifications}, Client, ClientSession, Collection, IndexModel};
+use mongodb::event::{cmap::CmapEvent, EventHandler};
+use mongodb::error::{ErrorKind as MongoErrorKind, WriteFailure};
+use mongodb::bson::{oid::ObjectId, doc, spec::BinarySubtype, to_bson, Binary, Document};
+use chrono::{NaiveDateTime, Utc};
+use tracing::instrument;
+use mongodb_net::{Task, Priority, Project, ProjectDocument, TaskDocument, TaskTree, DateTimeOutOfRangeError, HealthCheck, TagMatch};
+use task_store::{PoolConfig, PoolStatus, TaskStore, Error as StoreError};
 use std::str::FromStr;
 use thiserror::{Error as ThisError};
 
@@ -10,133 +18,885 @@ use thiserror::{Error as ThisError};
 pub enum Error {
 
     #[error("MongoDB Error: {0}")]
-    MongoDbError(#[from] mongodb::error::Error),
+    MongoDb(#[from] mongodb::error::Error),
 
     #[error("BSON Serialization error: {0}")]
-    BSONSerializationError(#[from] mongodb::bson::ser::Error),
+    BSONSerialization(#[from] mongodb::bson::ser::Error),
 
     #[error("ObjectId error: {0}")]
-    ObjectIdError(#[from] mongodb::bson::oid::Error),
+    ObjectId(#[from] mongodb::bson::oid::Error),
 
     #[error("DateTime out of range: {0}")]
     DateOutOfRange(#[from] DateTimeOutOfRangeError),
 
+    #[error("Task not found.")]
+    NotFound,
+
+    #[error("Project not found.")]
+    ProjectNotFound,
+
     #[error("Error: {0}")]
     Custom(String),
 
 }
 
+// Server error codes we report as something more specific than a database error.
+const DUPLICATE_KEY: i32 = 11000;
+const DOCUMENT_VALIDATION_FAILURE: i32 = 121;
+const UNAUTHORIZED: i32 = 13;
+
+fn mongo_error_code(e: &mongodb::error::Error) -> Option<i32> {
+    match e.kind.as_ref() {
+        MongoErrorKind::Command(e) => Some(e.code),
+        MongoErrorKind::Write(WriteFailure::WriteError(e)) => Some(e.code),
+        _ => None,
+    }
+}
+
+impl From<Error> for StoreError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::NotFound => StoreError::NotFound,
+            Error::ProjectNotFound => StoreError::ProjectNotFound,
+            Error::ObjectId(e) => StoreError::InvalidId(e.to_string()),
+            Error::MongoDb(e) => match (e.kind.as_ref(), mongo_error_code(&e)) {
+                (_, Some(DUPLICATE_KEY)) => StoreError::Conflict("duplicate key".to_string()),
+                (_, Some(DOCUMENT_VALIDATION_FAILURE)) => {
+                    StoreError::Validation("document failed validation".to_string())
+                },
+                (MongoErrorKind::Authentication { message, .. }, _) => StoreError::Unauthorized(message.clone()),
+                (_, Some(UNAUTHORIZED)) => StoreError::Unauthorized(e.to_string()),
+                (MongoErrorKind::ServerSelection { .. } | MongoErrorKind::Io(_), _) => StoreError::unavailable(e),
+                _ => StoreError::database(e),
+            },
+            e => StoreError::database(e),
+        }
+    }
+}
+
+// The unique index on project names is the only one a project write can break.
+fn project_name_error(e: Error, name: &str) -> StoreError {
+    match &e {
+        Error::MongoDb(mongo) if mongo_error_code(mongo) == Some(DUPLICATE_KEY) => {
+            StoreError::Conflict(format!("a project named {:?} already exists", name))
+        },
+        _ => e.into(),
+    }
+}
+
+// The driver has no pool statistics to ask for, so they're kept up to date from its pool events.
+struct PoolCounters {
+    open: AtomicU32,
+    in_use: AtomicU32,
+    max: u32,
+}
+
+impl PoolCounters {
+
+    fn record(&self, event: CmapEvent) {
+        let counter = match event {
+            CmapEvent::ConnectionCreated(_) => &self.open,
+            CmapEvent::ConnectionClosed(_) => {
+                let _ = self.open.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+                return;
+            },
+            CmapEvent::ConnectionCheckedOut(_) => &self.in_use,
+            CmapEvent::ConnectionCheckedIn(_) => {
+                let _ = self.in_use.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+                return;
+            },
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+}
+
+// Transactions need MongoDB to run as a replica set; a standalone server rejects them.
 #[derive(Clone)]
 pub struct TaskMongoDb {
-    tasks_collection: Collection<TaskDocument>
+    client: Client,
+    pool: Arc<PoolCounters>,
+    tasks_collection: Collection<TaskDocument>,
+    projects_collection: Collection<ProjectDocument>,
+    // Saved responses by idempotency key, `{ _id: key, response: binary, created_at: millis }`
+    idempotency_collection: Collection<Document>,
+    // One `{ _id: hierarchy, changes: n }` per task hierarchy, see `lock_hierarchy`
+    locks_collection: Collection<Document>,
+    session: Option<Arc<Mutex<ClientSession>>>,
 }
 
 impl TaskMongoDb {
 
-    pub async fn connect(url: &str) -> Result<Self, Error> {
-        let client = Client::with_uri_str(url).await?;
-        let tasks_collection = client.database("task_manager").collection::<TaskDocument>("tasks");
+    pub async fn connect(url: &str, pool: PoolConfig) -> Result<Self, Error> {
+        let mut options = ClientOptions::parse(url).await?;
+        options.max_pool_size = Some(pool.max_connections);
+        options.connect_timeout = Some(pool.timeout);
+        options.server_selection_timeout = Some(pool.timeout);
+        let counters = Arc::new(PoolCounters {
+            open: AtomicU32::new(0),
+            in_use: AtomicU32::new(0),
+            max: pool.max_connections,
+        });
+        let events = counters.clone();
+        options.cmap_event_handler = Some(EventHandler::callback(move |event| events.record(event)));
+        let client = Client::with_options(options)?;
+        let database = client.database("task_manager");
+        let tasks_collection = database.collection::<TaskDocument>("tasks");
+        let projects_collection = database.collection::<ProjectDocument>("projects");
+        let idempotency_collection = database.collection::<Document>("idempotency_keys");
+        let locks_collection = database.collection::<Document>("locks");
+        // Only open tasks are ever looked up by due date. Creating an existing index is a no-op.
+        let due_at_index = IndexModel::builder()
+            .keys(doc!{ "due_at": 1 })
+            .options(IndexOptions::builder().partial_filter_expression(doc!{ "completed": false }).build())
+            .build();
+        tasks_collection.create_index(due_at_index).await?;
+        // Multikey, one entry per tag of every task
+        tasks_collection.create_index(IndexModel::builder().keys(doc!{ "tags": 1 }).build()).await?;
+        tasks_collection.create_index(IndexModel::builder().keys(doc!{ "project_id": 1 }).build()).await?;
+        tasks_collection.create_index(IndexModel::builder().keys(doc!{ "parent_id": 1 }).build()).await?;
+        let name_index = IndexModel::builder()
+            .keys(doc!{ "name": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        projects_collection.create_index(name_index).await?;
         Ok (
             Self {
-                tasks_collection
+                client,
+                pool: counters,
+                tasks_collection,
+                projects_collection,
+                idempotency_collection,
+                locks_collection,
+                session: None,
             }
         )
     }
 
-    pub async fn new_task(&self, title: &str, priority: Priority) -> Result<Task, Error> {
-        let task_id = ObjectId::new().to_hex();
-        let task = Task::new(&task_id, title, priority, Utc::now().naive_utc());
+    // Ends server sessions and closes the pooled connections, waiting for ones still in use.
+    pub async fn close(&self) {
+        self.client.clone().shutdown().await;
+    }
+
+    // Every helper below runs inside the session's transaction when there is one.
+
+    async fn insert_task(&self, task: Task) -> Result<Task, Error> {
         let task_doc = task.as_document()?;
-        self.tasks_collection.insert_one(task_doc).await?;
+        let insert = self.tasks_collection.insert_one(task_doc);
+        match &self.session {
+            Some(session) => insert.session(&mut *session.lock().await).await?,
+            None => insert.await?,
+        };
         Ok(task)
     }
 
-    pub async fn pending_tasks(&self) -> Result<Vec<Task>, Error> {
-        let filter = doc!{ "completed": false };
-        let cursor = self.tasks_collection.find(filter).await?;
-        let pending_tasks: Vec<Task> = cursor
-            .try_collect::<Vec<TaskDocument>>()
-            .await?
+    async fn find_tasks(&self, filter: Document, sort: Option<Document>) -> Result<Vec<Task>, Error> {
+        let mut find = self.tasks_collection.find(filter);
+        if let Some(sort) = sort {
+            find = find.sort(sort);
+        }
+        let task_docs: Vec<TaskDocument> = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = find.session(&mut *session).await?;
+                cursor.stream(&mut session).try_collect().await?
+            },
+            None => find.await?.try_collect().await?,
+        };
+        let tasks: Vec<Task> = task_docs
             .into_iter()
             .map(|doc| doc.as_task())
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(pending_tasks)
+        Ok(tasks)
     }
 
-    pub async fn done_tasks(&self) -> Result<Vec<Task>, Error> {
-        let filter = doc!{ "completed": true };
-        let cursor = self.tasks_collection.find(filter).await?;
-        let completed_tasks: Vec<Task> = cursor
-            .try_collect::<Vec<TaskDocument>>()
-            .await?
-            .into_iter()
-            .map(|doc| doc.as_task())
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(completed_tasks)
+    async fn find_task(&self, task_id: &str) -> Result<Task, Error> {
+        let oid = ObjectId::from_str(task_id)?;
+        let filter = doc!{ "_id": oid };
+        let find = self.tasks_collection.find_one(filter);
+        let task_doc = match &self.session {
+            Some(session) => find.session(&mut *session.lock().await).await?,
+            None => find.await?,
+        };
+        match task_doc {
+            Some(task_doc) => Ok(task_doc.as_task()?),
+            None => Err(Error::NotFound),
+        }
     }
 
-    pub async fn mark_task_done(&self, task_id: &str) -> Result<Task, Error> {
-        let oid = ObjectId::from_str(&task_id)?;
+    async fn update_task(&self, task_id: &str, update: impl Into<UpdateModifications>) -> Result<Task, Error> {
+        let oid = ObjectId::from_str(task_id)?;
         let filter = doc!{ "_id": oid };
+        let find_and_update = self.tasks_collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After);
+        let updated_task = match &self.session {
+            Some(session) => find_and_update.session(&mut *session.lock().await).await?,
+            None => find_and_update.await?,
+        };
+        match updated_task {
+            Some(task_doc) => Ok(task_doc.as_task()?),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    async fn delete_task_doc(&self, task_id: &str) -> Result<Task, Error> {
+        let oid = ObjectId::from_str(task_id)?;
+        let filter = doc!{ "_id": oid };
+        let delete = self.tasks_collection.find_one_and_delete(filter);
+        let deleted_task = match &self.session {
+            Some(session) => delete.session(&mut *session.lock().await).await?,
+            None => delete.await?,
+        };
+        match deleted_task {
+            Some(task_doc) => Ok(task_doc.as_task()?),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    async fn has_open_subtasks(&self, task_id: ObjectId) -> Result<bool, Error> {
+        let filter = doc!{ "parent_id": task_id, "completed": false, "archived_at": null };
+        let count = self.tasks_collection.count_documents(filter).limit(1);
+        let open = match &self.session {
+            Some(session) => count.session(&mut *session.lock().await).await?,
+            None => count.await?,
+        };
+        Ok(open > 0)
+    }
+
+    async fn has_open_blockers(&self, task: &Task) -> Result<bool, Error> {
+        let blockers = task.get_blocked_by().iter().map(|id| ObjectId::from_str(id)).collect::<Result<Vec<_>, _>>()?;
+        if blockers.is_empty() {
+            return Ok(false);
+        }
+        let filter = doc!{ "_id": doc!{ "$in": blockers }, "completed": false, "archived_at": null };
+        let count = self.tasks_collection.count_documents(filter).limit(1);
+        let open = match &self.session {
+            Some(session) => count.session(&mut *session.lock().await).await?,
+            None => count.await?,
+        };
+        Ok(open > 0)
+    }
+
+    // Ids of the subtasks below the task at any depth, `NotFound` if the task itself doesn't exist.
+    async fn descendant_ids(&self, task_id: ObjectId) -> Result<Vec<ObjectId>, Error> {
+        self.linked_ids(task_id, "_id", "parent_id").await
+    }
+
+    // Ids of the tasks the task waits on, directly or through other tasks.
+    async fn blocker_ids(&self, task_id: ObjectId) -> Result<Vec<ObjectId>, Error> {
+        self.linked_ids(task_id, "blocked_by", "_id").await
+    }
+
+    // Follows `from` of a task to the tasks whose `to` matches it, and on from those.
+    async fn linked_ids(&self, task_id: ObjectId, from: &str, to: &str) -> Result<Vec<ObjectId>, Error> {
+        let pipeline = vec![
+            doc!{ "$match": doc!{ "_id": task_id } },
+            doc!{ "$graphLookup": doc!{
+                "from": "tasks",
+                "startWith": format!("${}", from),
+                "connectFromField": from,
+                "connectToField": to,
+                "as": "linked",
+            } },
+            doc!{ "$project": doc!{ "linked._id": 1 } },
+        ];
+        let aggregate = self.tasks_collection.aggregate(pipeline);
+        let found: Vec<Document> = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = aggregate.session(&mut *session).await?;
+                cursor.stream(&mut session).try_collect().await?
+            },
+            None => aggregate.await?.try_collect().await?,
+        };
+        let root = found.first().ok_or(Error::NotFound)?;
+        let linked = root
+            .get_array("linked")
+            .map_err(|e| Error::Custom(format!("Malformed task lookup: {}", e)))?;
+        linked
+            .iter()
+            .map(|task| {
+                task
+                    .as_document()
+                    .and_then(|task| task.get_object_id("_id").ok())
+                    .ok_or_else(|| Error::Custom("Malformed task lookup".to_string()))
+            })
+            .collect()
+    }
+
+    // Nothing enforces references between collections, so a task's project is looked up before it
+    // is set. A project deleted in between still leaves the task pointing nowhere.
+    async fn check_project(&self, project_id: Option<&str>) -> Result<Option<ObjectId>, Error> {
+        let Some(project_id) = project_id else {
+            return Ok(None);
+        };
+        let oid = ObjectId::from_str(project_id)?;
+        let find = self.projects_collection.find_one(doc!{ "_id": oid });
+        let project_doc = match &self.session {
+            Some(session) => find.session(&mut *session.lock().await).await?,
+            None => find.await?,
+        };
+        match project_doc {
+            Some(_) => Ok(Some(oid)),
+            None => Err(Error::ProjectNotFound),
+        }
+    }
+
+    async fn insert_project(&self, project_doc: ProjectDocument) -> Result<Project, Error> {
+        let project = project_doc.as_project()?;
+        let insert = self.projects_collection.insert_one(project_doc);
+        match &self.session {
+            Some(session) => insert.session(&mut *session.lock().await).await?,
+            None => insert.await?,
+        };
+        Ok(project)
+    }
+
+    async fn update_project(&self, project_id: &str, name: &str) -> Result<Project, Error> {
+        let oid = ObjectId::from_str(project_id)?;
+        let find_and_update = self.projects_collection
+            .find_one_and_update(doc!{ "_id": oid }, doc!{ "$set": doc!{ "name": name } })
+            .return_document(ReturnDocument::After);
+        let updated_project = match &self.session {
+            Some(session) => find_and_update.session(&mut *session.lock().await).await?,
+            None => find_and_update.await?,
+        };
+        match updated_project {
+            Some(project_doc) => Ok(project_doc.as_project()?),
+            None => Err(Error::ProjectNotFound),
+        }
+    }
+
+    // Takes the tasks out of the project first, so a failure in between never leaves tasks in a
+    // project that is gone.
+    async fn delete_project_doc(&self, project_id: &str) -> Result<Project, Error> {
+        let oid = ObjectId::from_str(project_id)?;
+        let unassign = self.tasks_collection
+            .update_many(doc!{ "project_id": oid }, doc!{ "$set": doc!{ "project_id": null } });
+        match &self.session {
+            Some(session) => unassign.session(&mut *session.lock().await).await?,
+            None => unassign.await?,
+        };
+        let delete = self.projects_collection.find_one_and_delete(doc!{ "_id": oid });
+        let deleted_project = match &self.session {
+            Some(session) => delete.session(&mut *session.lock().await).await?,
+            None => delete.await?,
+        };
+        match deleted_project {
+            Some(project_doc) => Ok(project_doc.as_project()?),
+            None => Err(Error::ProjectNotFound),
+        }
+    }
+
+    async fn find_projects(&self) -> Result<Vec<Project>, Error> {
+        let find = self.projects_collection.find(doc!{}).sort(doc!{ "_id": 1 });
+        let project_docs: Vec<ProjectDocument> = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = find.session(&mut *session).await?;
+                cursor.stream(&mut session).try_collect().await?
+            },
+            None => find.await?.try_collect().await?,
+        };
+        let projects = project_docs
+            .iter()
+            .map(ProjectDocument::as_project)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(projects)
+    }
+
+    // Opens a transaction unless one is open already, and hands back the store bound to it. So a
+    // check and the update depending on it see the same data.
+    async fn own_transaction(&self) -> Result<Option<Self>, StoreError> {
+        match self.session {
+            Some(_) => Ok(None),
+            None => Ok(Some(self.begin().await?)),
+        }
+    }
+
+    // Transactions only conflict on documents they both write, so two changes to different tasks
+    // could each pass their cycle check and close a cycle together. Every change to a hierarchy
+    // bumps its lock document first, which makes all but one of a concurrent pair fail instead.
+    async fn lock_hierarchy(&self, hierarchy: &str) -> Result<Option<Self>, StoreError> {
+        let own = self.own_transaction().await?;
+        let store = own.as_ref().unwrap_or(self);
+        if let Some(session) = &store.session {
+            store.locks_collection
+                .update_one(doc!{ "_id": hierarchy }, doc!{ "$inc": doc!{ "changes": 1 } })
+                .upsert(true)
+                .session(&mut *session.lock().await)
+                .await
+                .map_err(Error::from)?;
+        }
+        Ok(own)
+    }
+
+    // Commits the transaction `own_transaction` opened if there is one and `result` is a success,
+    // aborts it otherwise. The operation's own error is the one worth reporting.
+    async fn finish_own<T>(own: Option<Self>, result: Result<T, StoreError>) -> Result<T, StoreError> {
+        let Some(own) = own else {
+            return result;
+        };
+        match result {
+            Ok(value) => own.commit().await.map(|_| value),
+            Err(e) => {
+                let _ = own.rollback().await;
+                Err(e)
+            },
+        }
+    }
+
+    async fn complete_task(&self, task_oid: ObjectId) -> Result<Task, StoreError> {
+        let task_id = task_oid.to_hex();
+        if self.has_open_subtasks(task_oid).await? {
+            return Err(StoreError::Conflict("the task still has open subtasks".to_string()));
+        }
+        if self.has_open_blockers(&self.find_task(&task_id).await?).await? {
+            return Err(StoreError::Conflict("the task is still blocked by open tasks".to_string()));
+        }
+        // Update pipeline, so a task that is already done keeps its original completion time
+        let update = vec![doc!{
+            "$set": doc!{
+                "completed": true,
+                "completed_at": doc!{ "$ifNull": ["$completed_at", Utc::now().timestamp_millis()] },
+            }
+        }];
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    // Walks up from the task, completing each parent left without open subtasks or blockers.
+    async fn complete_parents(&self, task_id: &str) -> Result<(), StoreError> {
+        let mut current = self.find_task(task_id).await?;
+        while let Some(parent_id) = current.get_parent_id() {
+            let parent_oid = ObjectId::from_str(parent_id).map_err(Error::from)?;
+            if self.has_open_subtasks(parent_oid).await? || self.has_open_blockers(&self.find_task(parent_id).await?).await? {
+                break;
+            }
+            let update = vec![doc!{
+                "$set": doc!{
+                    "completed": true,
+                    "completed_at": doc!{ "$ifNull": ["$completed_at", Utc::now().timestamp_millis()] },
+                }
+            }];
+            // Only a parent that is still open, one already done ends the walk
+            let complete = self.tasks_collection
+                .find_one_and_update(doc!{ "_id": parent_oid, "completed": false }, update)
+                .return_document(ReturnDocument::After);
+            let completed = match &self.session {
+                Some(session) => complete.session(&mut *session.lock().await).await.map_err(Error::from)?,
+                None => complete.await.map_err(Error::from)?,
+            };
+            match completed {
+                Some(parent_doc) => current = parent_doc.as_task().map_err(Error::from)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_parent(&self, task_oid: ObjectId, parent_oid: Option<ObjectId>) -> Result<Task, StoreError> {
+        // Walk up from the new parent, the task must not be among its ancestors
+        let mut ancestor = parent_oid;
+        while let Some(oid) = ancestor {
+            if oid == task_oid {
+                return Err(StoreError::Validation("A task can't be moved below itself or one of its subtasks".to_string()));
+            }
+            let parent = self.find_task(&oid.to_hex()).await?;
+            ancestor = parent.get_parent_id().map(ObjectId::from_str).transpose().map_err(Error::from)?;
+        }
         let update = doc!{
-            "$set": doc!{ "completed": true }
+            "$set": doc!{ "parent_id": parent_oid }
         };
-        let updated_task = self.tasks_collection
-            .find_one_and_update(filter, update)
-            .return_document(ReturnDocument::After)
-            .await?;
-        let updated_task: Task = match updated_task {
-            Some(task_doc) => task_doc.as_task()?,
-            None => return Err(Error::Custom("Task not found.".to_string())),
+        Ok(self.update_task(&task_oid.to_hex(), update).await?)
+    }
+
+    async fn add_blocker(&self, task_oid: ObjectId, blocker_oid: ObjectId) -> Result<Task, StoreError> {
+        // Everything the blocker waits on, itself included; the task must not be among it
+        let mut blockers = self.blocker_ids(blocker_oid).await?;
+        blockers.push(blocker_oid);
+        if blockers.contains(&task_oid) {
+            return Err(StoreError::Validation("A task can't wait on itself, directly or through other tasks".to_string()));
+        }
+        let update = doc!{
+            "$addToSet": doc!{ "blocked_by": blocker_oid }
         };
+        Ok(self.update_task(&task_oid.to_hex(), update).await?)
+    }
 
-        Ok(updated_task)
+    fn take_session(self) -> Result<Option<ClientSession>, StoreError> {
+        match self.session {
+            Some(session) => {
+                let session = Arc::try_unwrap(session)
+                    .map_err(|_| StoreError::Transaction("transaction is still in use".to_string()))?;
+                Ok(Some(session.into_inner()))
+            },
+            None => Ok(None),
+        }
+    }
+
+}
+
+impl TaskStore for TaskMongoDb {
+
+    type Id = String;
+    type Task = Task;
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn begin(&self) -> Result<Self, StoreError> {
+        if self.session.is_some() {
+            return Err(StoreError::Transaction("a transaction is already open".to_string()));
+        }
+        let mut session = self.client.start_session().await.map_err(Error::from)?;
+        session.start_transaction().await.map_err(Error::from)?;
+        Ok(Self {
+            client: self.client.clone(),
+            pool: self.pool.clone(),
+            tasks_collection: self.tasks_collection.clone(),
+            projects_collection: self.projects_collection.clone(),
+            idempotency_collection: self.idempotency_collection.clone(),
+            locks_collection: self.locks_collection.clone(),
+            session: Some(Arc::new(Mutex::new(session))),
+        })
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn commit(self) -> Result<(), StoreError> {
+        if let Some(mut session) = self.take_session()? {
+            session.commit_transaction().await.map_err(Error::from)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rollback(self) -> Result<(), StoreError> {
+        if let Some(mut session) = self.take_session()? {
+            session.abort_transaction().await.map_err(Error::from)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_task(&self, title: &str, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<String>) -> Result<Task, StoreError> {
+        self.check_project(project_id.as_deref()).await?;
+        let task_id = ObjectId::new().to_hex();
+        let task = Task::new(&task_id, title, priority, Utc::now().naive_utc())
+            .with_due_at(due_at)
+            .with_project_id(project_id);
+        Ok(self.insert_task(task).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn pending_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{ "completed": false, "archived_at": null };
+        Ok(self.find_tasks(filter, None).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn done_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{ "completed": true, "archived_at": null };
+        Ok(self.find_tasks(filter, None).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn mark_task_done(&self, task_id: String) -> Result<Task, StoreError> {
+        let oid = ObjectId::from_str(&task_id).map_err(Error::from)?;
+        let own = self.own_transaction().await?;
+        let result = own.as_ref().unwrap_or(self).complete_task(oid).await;
+        Self::finish_own(own, result).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn reopen_task(&self, task_id: String) -> Result<Task, StoreError> {
+        let update = doc!{
+            "$set": doc!{ "completed": false, "completed_at": null }
+        };
+        Ok(self.update_task(&task_id, update).await?)
     }
 
-    pub async fn edit_task_title(&self, task_id: &str, title: &str) -> Result<Task, Error> {
-        let oid = ObjectId::from_str(&task_id)?;
-        let filter = doc!{"_id": oid };
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_title(&self, task_id: String, title: &str) -> Result<Task, StoreError> {
         let update = doc!{
             "$set": doc!{ "title": title }
         };
-        let updated_task = self.tasks_collection
-            .find_one_and_update(filter, update).
-            return_document(ReturnDocument::After)
-            .await?;
-        let updated_task: Task = match updated_task {
-            Some(task_doc) => task_doc.as_task()?,
-            None => return Err(Error::Custom("Task not found.".to_string())),
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_priority(&self, task_id: String, priority: Priority) -> Result<Task, StoreError> {
+        let priority = to_bson(&priority).map_err(Error::from)?;
+        let update = doc!{
+            "$set": doc!{ "priority": priority }
         };
+        Ok(self.update_task(&task_id, update).await?)
+    }
 
-        Ok(updated_task)
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn query_task_by_id(&self, task_id: String) -> Result<Task, StoreError> {
+        Ok(self.find_task(&task_id).await?)
     }
 
-    pub async fn edit_task_priority(&self, task_id: &str, priority: Priority) -> Result<Task, Error> {
-        let oid = ObjectId::from_str(&task_id)?;
-        let priority = to_bson(&priority)?;
-        let filter = doc!{ "_id": oid };
+    // Subtasks go along with their parent, like `ON DELETE CASCADE` does in the SQL backends. They
+    // are deleted first, so a failure in between never leaves subtasks without their parent. Tasks
+    // waiting on any of them stop doing so afterwards.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_task(&self, task_id: String) -> Result<Task, StoreError> {
+        let oid = ObjectId::from_str(&task_id).map_err(Error::from)?;
+        let mut deleted = self.descendant_ids(oid).await?;
+        if !deleted.is_empty() {
+            let delete = self.tasks_collection.delete_many(doc!{ "_id": doc!{ "$in": deleted.clone() } });
+            match &self.session {
+                Some(session) => delete.session(&mut *session.lock().await).await.map_err(Error::from)?,
+                None => delete.await.map_err(Error::from)?,
+            };
+        }
+        let task = self.delete_task_doc(&task_id).await?;
+        deleted.push(oid);
+        let unblock = self.tasks_collection.update_many(
+            doc!{ "blocked_by": doc!{ "$in": deleted.clone() } },
+            doc!{ "$pull": doc!{ "blocked_by": doc!{ "$in": deleted } } },
+        );
+        match &self.session {
+            Some(session) => unblock.session(&mut *session.lock().await).await.map_err(Error::from)?,
+            None => unblock.await.map_err(Error::from)?,
+        };
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archive_task(&self, task_id: String) -> Result<Task, StoreError> {
         let update = doc!{
-            "$set": doc!{ "priority": priority }
+            "$set": doc!{ "archived_at": Utc::now().timestamp_millis() }
         };
-        let updated_task = self.tasks_collection
-            .find_one_and_update(filter, update)
-            .return_document(ReturnDocument::After)
-            .await?;
-        let updated_task: Task = match updated_task {
-            Some(task_doc) => task_doc.as_task()?,
-            None => return Err(Error::Custom("Task not found.".to_string())),
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn unarchive_task(&self, task_id: String) -> Result<Task, StoreError> {
+        let update = doc!{
+            "$set": doc!{ "archived_at": null }
+        };
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archived_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{ "archived_at": doc!{ "$ne": null } };
+        Ok(self.find_tasks(filter, None).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn set_due_date(&self, task_id: String, due_at: Option<NaiveDateTime>) -> Result<Task, StoreError> {
+        let update = doc!{
+            "$set": doc!{ "due_at": due_at.map(|due_at| due_at.and_utc().timestamp_millis()) }
+        };
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    // Both due date queries fall under the partial index on `due_at`.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn overdue_tasks(&self, now: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{
+            "completed": false,
+            "archived_at": null,
+            "due_at": doc!{ "$lt": now.and_utc().timestamp_millis() },
+        };
+        Ok(self.find_tasks(filter, Some(doc!{ "due_at": 1 })).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn due_tasks(&self, from: NaiveDateTime, until: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{
+            "completed": false,
+            "archived_at": null,
+            "due_at": doc!{
+                "$gte": from.and_utc().timestamp_millis(),
+                "$lte": until.and_utc().timestamp_millis(),
+            },
+        };
+        Ok(self.find_tasks(filter, Some(doc!{ "due_at": 1 })).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_tag(&self, task_id: String, tag: &str) -> Result<Task, StoreError> {
+        let update = doc!{
+            "$addToSet": doc!{ "tags": tag }
+        };
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_tag(&self, task_id: String, tag: &str) -> Result<Task, StoreError> {
+        let update = doc!{
+            "$pull": doc!{ "tags": tag }
         };
-        Ok(updated_task)
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_with_tag(&self, tags: &[String], matching: TagMatch) -> Result<Vec<Task>, StoreError> {
+        let operator = match matching {
+            TagMatch::All => "$all",
+            TagMatch::Any => "$in",
+        };
+        let filter = doc!{ "archived_at": null, "tags": doc!{ operator: tags } };
+        Ok(self.find_tasks(filter, Some(doc!{ "_id": 1 })).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_project(&self, name: &str) -> Result<Project, StoreError> {
+        let project_doc = ProjectDocument::new(name, Utc::now().timestamp_millis());
+        self.insert_project(project_doc).await.map_err(|e| project_name_error(e, name))
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rename_project(&self, project_id: String, name: &str) -> Result<Project, StoreError> {
+        self.update_project(&project_id, name).await.map_err(|e| project_name_error(e, name))
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_project(&self, project_id: String) -> Result<Project, StoreError> {
+        Ok(self.delete_project_doc(&project_id).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn projects(&self) -> Result<Vec<Project>, StoreError> {
+        Ok(self.find_projects().await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_task(&self, task_id: String, project_id: Option<String>) -> Result<Task, StoreError> {
+        let project_oid = self.check_project(project_id.as_deref()).await?;
+        let update = doc!{
+            "$set": doc!{ "project_id": project_oid }
+        };
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn project_tasks(&self, project_id: String, completed: bool) -> Result<Vec<Task>, StoreError> {
+        let oid = ObjectId::from_str(&project_id).map_err(Error::from)?;
+        let filter = doc!{ "project_id": oid, "completed": completed, "archived_at": null };
+        Ok(self.find_tasks(filter, Some(doc!{ "_id": 1 })).await?)
     }
 
-    pub async fn query_task_by_id(&self, id: &str) -> Result<Task, Error> {
-        let oid = ObjectId::from_str(&id)?;
-        let filter = doc!{ "_id": oid};
-        if let Some(task_doc) = self.tasks_collection.find_one(filter).await? {
-            Ok(task_doc.as_task()?)
-        } else {
-            Err(Error::Custom("Couldn't find such task.".to_string()))
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_subtask(&self, parent_id: String, title: &str, priority: Priority) -> Result<Task, StoreError> {
+        let parent = self.find_task(&parent_id).await?;
+        let task_id = ObjectId::new().to_hex();
+        let task = Task::new(&task_id, title, priority, Utc::now().naive_utc())
+            .with_project_id(parent.get_project_id().map(str::to_string))
+            .with_parent_id(Some(parent_id));
+        Ok(self.insert_task(task).await?)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_subtask(&self, task_id: String, parent_id: Option<String>) -> Result<Task, StoreError> {
+        let task_oid = ObjectId::from_str(&task_id).map_err(Error::from)?;
+        let parent_oid = parent_id.as_deref().map(ObjectId::from_str).transpose().map_err(Error::from)?;
+        let own = self.lock_hierarchy("tasks.parent_id").await?;
+        let result = own.as_ref().unwrap_or(self).set_parent(task_oid, parent_oid).await;
+        Self::finish_own(own, result).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn task_tree(&self, task_id: String) -> Result<TaskTree, StoreError> {
+        let oid = ObjectId::from_str(&task_id).map_err(Error::from)?;
+        let root = self.find_task(&task_id).await?;
+        let descendants = self.descendant_ids(oid).await?;
+        let descendants = self.find_tasks(doc!{ "_id": doc!{ "$in": descendants } }, Some(doc!{ "_id": 1 })).await?;
+        Ok(TaskTree::build(root, descendants, Task::get_id, |task| task.get_parent_id().map(str::to_string)))
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn complete_finished_parents(&self, task_id: String) -> Result<(), StoreError> {
+        let own = self.own_transaction().await?;
+        let result = own.as_ref().unwrap_or(self).complete_parents(&task_id).await;
+        Self::finish_own(own, result).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_dependency(&self, task_id: String, blocked_by: String) -> Result<Task, StoreError> {
+        let task_oid = ObjectId::from_str(&task_id).map_err(Error::from)?;
+        let blocker_oid = ObjectId::from_str(&blocked_by).map_err(Error::from)?;
+        let own = self.lock_hierarchy("task_dependencies").await?;
+        let result = own.as_ref().unwrap_or(self).add_blocker(task_oid, blocker_oid).await;
+        Self::finish_own(own, result).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_dependency(&self, task_id: String, blocked_by: String) -> Result<Task, StoreError> {
+        let blocker_oid = ObjectId::from_str(&blocked_by).map_err(Error::from)?;
+        let update = doc!{
+            "$pull": doc!{ "blocked_by": blocker_oid }
+        };
+        Ok(self.update_task(&task_id, update).await?)
+    }
+
+    // A blocker that is open and unarchived is one of the open, unarchived tasks listed anyway.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_by_readiness(&self, ready: bool) -> Result<Vec<Task>, StoreError> {
+        let filter = doc!{ "completed": false, "archived_at": null };
+        let open = self.find_tasks(filter, Some(doc!{ "_id": 1 })).await?;
+        let open_ids: HashSet<String> = open.iter().map(Task::get_id).collect();
+        let tasks = open
+            .into_iter()
+            .filter(|task| task.get_blocked_by().iter().any(|id| open_ids.contains(id)) != ready)
+            .collect();
+        Ok(tasks)
+    }
+
+    // Saved responses live outside of any transaction, the server only touches them between requests.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn saved_response(&self, key: &str, since: NaiveDateTime) -> Result<Option<Vec<u8>>, StoreError> {
+        let filter = doc!{ "_id": key, "created_at": doc!{ "$gt": since.and_utc().timestamp_millis() } };
+        let saved = self.idempotency_collection.find_one(filter).await.map_err(Error::from)?;
+        match saved {
+            Some(saved) => {
+                let response = saved
+                    .get_binary_generic("response")
+                    .map_err(|e| Error::Custom(format!("Malformed saved response: {}", e)))?;
+                Ok(Some(response.clone()))
+            },
+            None => Ok(None),
         }
     }
 
+    #[instrument(level = "debug", skip(self, response), err(level = "debug"))]
+    async fn save_response(&self, key: &str, response: &[u8], expired_before: NaiveDateTime) -> Result<(), StoreError> {
+        let expired = doc!{ "created_at": doc!{ "$lt": expired_before.and_utc().timestamp_millis() } };
+        self.idempotency_collection.delete_many(expired).await.map_err(Error::from)?;
+        let saved = doc!{
+            "_id": key,
+            "response": Binary { subtype: BinarySubtype::Generic, bytes: response.to_vec() },
+            "created_at": Utc::now().timestamp_millis(),
+        };
+        self.idempotency_collection
+            .replace_one(doc!{ "_id": key }, saved)
+            .upsert(true)
+            .await
+            .map_err(Error::from)?;
+        Ok(())
+    }
+
+    // MongoDB has no migrations, the collections are created on first write.
+    #[instrument(level = "debug", skip(self))]
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let check = match self.client.database("admin").run_command(doc!{ "ping": 1 }).await {
+            Ok(_) => HealthCheck::new("database", true),
+            Err(e) => HealthCheck::new("database", false).with_details(&e.to_string()),
+        };
+        vec![check]
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        let open = self.pool.open.load(Ordering::Relaxed);
+        Some(PoolStatus {
+            open,
+            idle: open.saturating_sub(self.pool.in_use.load(Ordering::Relaxed)),
+            max: self.pool.max,
+        })
+    }
+
 }
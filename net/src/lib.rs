@@ -1,6 +1,9 @@
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use sqlx::{Type, FromRow};
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
 
 #[derive(Type, Debug, Clone, Copy, Serialize, Deserialize)]
 #[sqlx(type_name = "priority")]
@@ -18,6 +21,19 @@ pub struct Task {
     pub completed: bool,
     pub id: i32,
     pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    pub archived_at: Option<NaiveDateTime>,
+    pub due_at: Option<NaiveDateTime>,
+    // Sorted and without duplicates. SQLite hands them over as a JSON array.
+    #[sqlx(json)]
+    pub tags: Vec<String>,
+    // `None` for tasks outside of any project.
+    pub project_id: Option<i32>,
+    // The task this one is a subtask of, `None` for top-level tasks.
+    pub parent_id: Option<i32>,
+    // Tasks that have to be done before this one, sorted. Like the tags, a JSON array from SQLite.
+    #[sqlx(json)]
+    pub blocked_by: Vec<i32>,
 }
 
 impl Task {
@@ -28,75 +44,604 @@ impl Task {
             priority,
             completed: false,
             id,
-            created_at
+            created_at,
+            completed_at: None,
+            archived_at: None,
+            due_at: None,
+            tags: Vec::new(),
+            project_id: None,
+            parent_id: None,
+            blocked_by: Vec::new(),
         }
     }
 
     pub fn format(&self) -> String {
-        format!("[{:?}]: {}", self.priority, self.title)
+        let mut line = format!("[{:?}]: {}", self.priority, self.title);
+        for tag in &self.tags {
+            line.push_str(" #");
+            line.push_str(tag);
+        }
+        if !self.blocked_by.is_empty() {
+            let blockers: Vec<String> = self.blocked_by.iter().map(i32::to_string).collect();
+            line.push_str(&format!(" [blocked by {}]", blockers.join(", ")));
+        }
+        match (self.completed_at, self.due_at) {
+            (Some(completed_at), _) => line.push_str(&format!(" (done {})", completed_at.format("%Y-%m-%d %H:%M"))),
+            (None, Some(due_at)) => line.push_str(&format!(" (due {})", relative_time(due_at, Utc::now().naive_utc()))),
+            (None, None) => {},
+        }
+        line
+    }
+
+}
+
+// A named list of tasks. Project names are unique.
+#[derive(FromRow, Debug, Serialize, Deserialize, Clone)]
+pub struct Project<Id = i32> {
+    pub id: Id,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+// A task with its subtasks, each with their own, every level in the order the backend listed them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TaskTree<T = Task> {
+    pub task: T,
+    pub subtasks: Vec<TaskTree<T>>,
+}
+
+impl<T> TaskTree<T> {
+
+    // Builds the tree under `root` from all of its descendants. `key` reads a task's id and
+    // `parent_key` the id of the task it's a subtask of.
+    pub fn build<K, F, P>(root: T, descendants: Vec<T>, key: F, parent_key: P) -> Self
+    where
+        K: Eq + Hash,
+        F: Fn(&T) -> K,
+        P: Fn(&T) -> Option<K>,
+    {
+        let mut children: HashMap<K, Vec<T>> = HashMap::new();
+        for task in descendants {
+            if let Some(parent) = parent_key(&task) {
+                children.entry(parent).or_default().push(task);
+            }
+        }
+        Self::attach(root, &mut children, &key)
+    }
+
+    fn attach<K, F>(task: T, children: &mut HashMap<K, Vec<T>>, key: &F) -> Self
+    where
+        K: Eq + Hash,
+        F: Fn(&T) -> K,
+    {
+        let subtasks = children
+            .remove(&key(&task))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| Self::attach(child, children, key))
+            .collect();
+        Self { task, subtasks }
+    }
+
+    // Every task of the tree with how deep below the root it is, each one followed by its subtasks.
+    pub fn flatten(&self) -> Vec<(usize, &T)> {
+        let mut tasks = Vec::new();
+        self.collect(0, &mut tasks);
+        tasks
+    }
+
+    fn collect<'a>(&'a self, depth: usize, tasks: &mut Vec<(usize, &'a T)>) {
+        tasks.push((depth, &self.task));
+        for subtask in &self.subtasks {
+            subtask.collect(depth + 1, tasks);
+        }
     }
 
 }
 
+// `at` as seen from `now`, e.g. "in 3 days" or "2 hours ago", rounded to the largest whole unit.
+pub fn relative_time(at: NaiveDateTime, now: NaiveDateTime) -> String {
+    let seconds = (at - now).num_seconds();
+    let minutes = (seconds.abs() + 30) / 60;
+    let (count, unit) = if minutes >= 2 * 24 * 60 {
+        ((minutes + 12 * 60) / (24 * 60), "day")
+    } else if minutes >= 60 {
+        ((minutes + 30) / 60, "hour")
+    } else {
+        (minutes, "minute")
+    };
+    let amount = format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" });
+    if minutes == 0 {
+        "now".to_string()
+    } else if seconds > 0 {
+        format!("in {}", amount)
+    } else {
+        format!("{} ago", amount)
+    }
+}
+
+// The protocol types are generic over the task id (`i32` for Postgres, hex `String` for MongoDB)
+// and over the task type returned by the storage backend.
 #[derive(Serialize, Deserialize, Clone)]
-pub enum Command {
-    NewTask{title: String, priority: Priority},
+pub enum Command<Id = i32> {
+    NewTask{title: String, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<Id>},
     PendingTasks,
     DoneTasks,
-    MarkTaskDone(i32),
-    EditTaskTitle{task_id: i32, new_title: String},
-    EditTaskPriority{task_id: i32, priority: Priority},
-    QueryTaskById(i32),
+    MarkTaskDone(Id),
+    EditTaskTitle{task_id: Id, new_title: String},
+    EditTaskPriority{task_id: Id, priority: Priority},
+    QueryTaskById(Id),
+    DeleteTask(Id),
+    ArchiveTask(Id),
+    UnarchiveTask(Id),
+    ArchivedTasks,
+    ReopenTask(Id),
+    // `None` clears the due date.
+    SetDueDate{task_id: Id, due_at: Option<NaiveDateTime>},
+    // Open tasks past their due date, soonest due first.
+    OverdueTasks,
+    // Open tasks due from now to `days` days from now, soonest due first. Overdue ones are left out.
+    DueWithin{days: u32},
+    // Tags are trimmed and lowercased by the server. Adding a tag the task already has, or removing
+    // one it doesn't have, changes nothing.
+    AddTag{task_id: Id, tag: String},
+    RemoveTag{task_id: Id, tag: String},
+    // Unarchived tasks carrying all or any of `tags`.
+    TasksWithTag{tags: Vec<String>, matching: TagMatch},
+    NewProject{name: String},
+    RenameProject{project_id: Id, name: String},
+    // Tasks of a deleted project are kept, outside of any project.
+    DeleteProject(Id),
+    Projects,
+    // `None` takes the task out of its project.
+    MoveTask{task_id: Id, project_id: Option<Id>},
+    // Like `PendingTasks` and `DoneTasks`, for the tasks of one project.
+    ProjectPendingTasks(Id),
+    ProjectDoneTasks(Id),
+    // Creates a task as a subtask of `parent_id`, in the same project as its parent.
+    AddSubtask{parent_id: Id, title: String, priority: Priority},
+    // Makes the task a subtask of `parent_id`, or a top-level task for `None`. A task can't end up
+    // below itself.
+    MoveSubtask{task_id: Id, parent_id: Option<Id>},
+    // The task with all of its subtasks, theirs included.
+    TaskTree(Id),
+    // `task_id` can't be done before `blocked_by` is. A dependency that would make a task wait on
+    // itself, directly or through other tasks, is refused. Adding one twice changes nothing.
+    AddDependency{task_id: Id, blocked_by: Id},
+    RemoveDependency{task_id: Id, blocked_by: Id},
+    // Open, unarchived tasks waiting on at least one open, unarchived task.
+    BlockedTasks,
+    // Open, unarchived tasks not waiting on any, so they can be worked on right away.
+    ReadyTasks,
+    // Answered without touching the database, tells the connection and the server are alive.
+    Ping,
+    // Runs the server's readiness checks, see `HealthReport`.
+    Health,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct ClientRequest {
-    commands: Vec<Command>,
+impl<Id> Command<Id> {
+    // Commands that don't change anything, so sending them twice is harmless.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::PendingTasks
+                | Command::DoneTasks
+                | Command::QueryTaskById(_)
+                | Command::ArchivedTasks
+                | Command::OverdueTasks
+                | Command::DueWithin { .. }
+                | Command::TasksWithTag { .. }
+                | Command::Projects
+                | Command::ProjectPendingTasks(_)
+                | Command::ProjectDoneTasks(_)
+                | Command::TaskTree(_)
+                | Command::BlockedTasks
+                | Command::ReadyTasks
+                | Command::Ping
+                | Command::Health
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::NewTask { .. } => "NewTask",
+            Command::PendingTasks => "PendingTasks",
+            Command::DoneTasks => "DoneTasks",
+            Command::MarkTaskDone(_) => "MarkTaskDone",
+            Command::EditTaskTitle { .. } => "EditTaskTitle",
+            Command::EditTaskPriority { .. } => "EditTaskPriority",
+            Command::QueryTaskById(_) => "QueryTaskById",
+            Command::DeleteTask(_) => "DeleteTask",
+            Command::ArchiveTask(_) => "ArchiveTask",
+            Command::UnarchiveTask(_) => "UnarchiveTask",
+            Command::ArchivedTasks => "ArchivedTasks",
+            Command::ReopenTask(_) => "ReopenTask",
+            Command::SetDueDate { .. } => "SetDueDate",
+            Command::OverdueTasks => "OverdueTasks",
+            Command::DueWithin { .. } => "DueWithin",
+            Command::AddTag { .. } => "AddTag",
+            Command::RemoveTag { .. } => "RemoveTag",
+            Command::TasksWithTag { .. } => "TasksWithTag",
+            Command::NewProject { .. } => "NewProject",
+            Command::RenameProject { .. } => "RenameProject",
+            Command::DeleteProject(_) => "DeleteProject",
+            Command::Projects => "Projects",
+            Command::MoveTask { .. } => "MoveTask",
+            Command::ProjectPendingTasks(_) => "ProjectPendingTasks",
+            Command::ProjectDoneTasks(_) => "ProjectDoneTasks",
+            Command::AddSubtask { .. } => "AddSubtask",
+            Command::MoveSubtask { .. } => "MoveSubtask",
+            Command::TaskTree(_) => "TaskTree",
+            Command::AddDependency { .. } => "AddDependency",
+            Command::RemoveDependency { .. } => "RemoveDependency",
+            Command::BlockedTasks => "BlockedTasks",
+            Command::ReadyTasks => "ReadyTasks",
+            Command::Ping => "Ping",
+            Command::Health => "Health",
+        }
+    }
+
+    // The task the command acts on, if it acts on a single existing one.
+    pub fn task_id(&self) -> Option<&Id> {
+        match self {
+            Command::MarkTaskDone(id)
+            | Command::QueryTaskById(id)
+            | Command::DeleteTask(id)
+            | Command::ArchiveTask(id)
+            | Command::UnarchiveTask(id)
+            | Command::ReopenTask(id)
+            | Command::TaskTree(id) => Some(id),
+            Command::EditTaskTitle { task_id, .. }
+            | Command::EditTaskPriority { task_id, .. }
+            | Command::SetDueDate { task_id, .. }
+            | Command::AddTag { task_id, .. }
+            | Command::RemoveTag { task_id, .. }
+            | Command::MoveTask { task_id, .. }
+            | Command::MoveSubtask { task_id, .. }
+            | Command::AddDependency { task_id, .. }
+            | Command::RemoveDependency { task_id, .. } => Some(task_id),
+            Command::NewTask { .. }
+            | Command::PendingTasks
+            | Command::DoneTasks
+            | Command::ArchivedTasks
+            | Command::OverdueTasks
+            | Command::DueWithin { .. }
+            | Command::TasksWithTag { .. }
+            | Command::NewProject { .. }
+            | Command::RenameProject { .. }
+            | Command::DeleteProject(_)
+            | Command::Projects
+            | Command::ProjectPendingTasks(_)
+            | Command::ProjectDoneTasks(_)
+            | Command::AddSubtask { .. }
+            | Command::BlockedTasks
+            | Command::ReadyTasks
+            | Command::Ping
+            | Command::Health => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagMatch {
+    All,
+    Any,
 }
 
-impl ClientRequest {
-    pub fn get_commands(&self) -> &[Command] {
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ClientRequest<Id = i32> {
+    commands: Vec<Command<Id>>,
+    atomic: bool,
+    // The server remembers the response to a keyed request for a while and answers a request with
+    // the same key from memory, so sending it again after a lost connection doesn't run it twice.
+    idempotency_key: Option<String>,
+}
+
+impl<Id: Clone> ClientRequest<Id> {
+    pub fn get_commands(&self) -> &[Command<Id>] {
         &self.commands
     }
 
-    pub fn new(cmds: &[Command]) -> Self {
+    pub fn is_atomic(&self) -> bool {
+        self.atomic
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.commands.iter().all(Command::is_read_only)
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    pub fn new(cmds: &[Command<Id>]) -> Self {
         Self {
             commands: cmds.to_vec(),
+            atomic: false,
+            idempotency_key: None,
+        }
+    }
+
+    // Commands run one after another in a single transaction, the first error rolls back all of them.
+    pub fn atomic(cmds: &[Command<Id>]) -> Self {
+        Self {
+            commands: cmds.to_vec(),
+            atomic: true,
+            idempotency_key: None,
+        }
+    }
+
+    // Keys are shared by every client of a server, so use one that is unique for the server's
+    // idempotency window, e.g. a UUID. Reusing a key for a different request gets it rejected.
+    pub fn with_idempotency_key(mut self, key: &str) -> Self {
+        self.idempotency_key = Some(key.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum CommandResponseValue<T = Task, Id = i32> {
+    NewTask(T),
+    PendingTasks(Vec<T>),
+    DoneTasks(Vec<T>),
+    MarkTaskDone(T),
+    EditTaskTitle(T),
+    EditTaskPriority(T),
+    QueryTaskById(T),
+    DeleteTask(T),
+    ArchiveTask(T),
+    UnarchiveTask(T),
+    ArchivedTasks(Vec<T>),
+    ReopenTask(T),
+    SetDueDate(T),
+    OverdueTasks(Vec<T>),
+    DueWithin(Vec<T>),
+    AddTag(T),
+    RemoveTag(T),
+    TasksWithTag(Vec<T>),
+    NewProject(Project<Id>),
+    RenameProject(Project<Id>),
+    DeleteProject(Project<Id>),
+    Projects(Vec<Project<Id>>),
+    MoveTask(T),
+    ProjectPendingTasks(Vec<T>),
+    ProjectDoneTasks(Vec<T>),
+    AddSubtask(T),
+    MoveSubtask(T),
+    TaskTree(TaskTree<T>),
+    AddDependency(T),
+    RemoveDependency(T),
+    BlockedTasks(Vec<T>),
+    ReadyTasks(Vec<T>),
+    Pong,
+    Health(HealthReport),
+}
+
+// One readiness check, e.g. whether the database answers or the migrations are applied.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthCheck {
+    pub name: String,
+    pub healthy: bool,
+    // Why the check failed, or what it saw when that's worth showing.
+    pub details: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn new(name: &str, healthy: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            healthy,
+            details: None,
         }
     }
+
+    pub fn with_details(mut self, details: &str) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    // Ready to take requests when every check passed.
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|check| check.healthy)
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            write!(f, "{}: {}", check.name, if check.healthy { "ok" } else { "failing" })?;
+            if let Some(details) = &check.details {
+                write!(f, " ({})", details)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    InvalidId,
+    // The command itself was rejected, e.g. an empty title or a violated check constraint.
+    ValidationFailed,
+    // The command clashes with existing data, e.g. a duplicate key.
+    Conflict,
+    Unauthorized,
+    // The database couldn't be reached, retrying later may succeed.
+    Unavailable,
+    // The request frame couldn't be decoded or was too large, no command was run.
+    MalformedRequest,
+    Internal,
+    // Sent unprompted when the server stops, no request was run and the connection is closed next.
+    ShuttingDown,
+}
+
+// `message` is meant for humans, `details` carries extra context like the offending field or
+// constraint when there is any.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommandError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl CommandError {
+    pub fn new(kind: ErrorKind, message: &str) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: &str) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)?;
+        if let Some(details) = &self.details {
+            write!(f, " ({})", details)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-pub enum CommandResponseValue {
-    NewTask(Task),
-    PendingTasks(Vec<Task>),
-    DoneTasks(Vec<Task>),
-    MarkTaskDone,
-    EditTaskTitle,
-    EditTaskPriority,
-    QueryTaskById(Task),
+pub enum CommandResult<T = Task, Id = i32> {
+    Success(CommandResponseValue<T, Id>),
+    Error(CommandError),
+    // Part of an atomic request that was rolled back because the command at `failed_index` failed.
+    Aborted{failed_index: u32},
 }
 
+// `index` is the position of the command in `ClientRequest::commands` that produced this result.
 #[derive(Serialize, Deserialize, Clone)]
-pub enum CommandResponse {
-    Success(CommandResponseValue),
-    Error(String),
+pub struct CommandResponse<T = Task, Id = i32> {
+    pub index: u32,
+    pub result: CommandResult<T, Id>,
+}
+
+impl<T, Id> CommandResponse<T, Id> {
+    pub fn new(index: u32, result: CommandResult<T, Id>) -> Self {
+        Self {
+            index,
+            result,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct ServerResponse {
-    payload: Vec<CommandResponse>
+pub struct ServerResponse<T = Task, Id = i32> {
+    payload: Vec<CommandResponse<T, Id>>,
+    // Set when the request as a whole was refused, `payload` is empty then.
+    error: Option<CommandError>,
 }
 
-impl ServerResponse {
-    pub fn new(payload: &[CommandResponse]) -> Self  {
+impl<T: Clone, Id: Clone> ServerResponse<T, Id> {
+    pub fn new(payload: &[CommandResponse<T, Id>]) -> Self  {
         Self {
             payload: payload.to_vec(),
+            error: None,
+        }
+    }
+
+    pub fn rejected(error: CommandError) -> Self {
+        Self {
+            payload: Vec::new(),
+            error: Some(error),
         }
     }
 
-    pub fn unwrap(&self) -> Vec<CommandResponse> {
+    pub fn error(&self) -> Option<&CommandError> {
+        self.error.as_ref()
+    }
+
+    pub fn unwrap(&self) -> Vec<CommandResponse<T, Id>> {
         self.payload.clone()
     }
 
+    // Result of the command at `index` in the request, whatever order the server answered in.
+    pub fn get(&self, index: usize) -> Option<&CommandResult<T, Id>> {
+        self.payload
+            .iter()
+            .find(|response| response.index as usize == index)
+            .map(|response| &response.result)
+    }
+
+}
+
+// Sent by the client as the very first frame of a connection, answered by a `HelloResponse`.
+// Bump `PROTOCOL_VERSION` whenever anything sent after the handshake changes shape; the handshake
+// frames themselves must keep their layout so mismatched peers can still tell each other so.
+pub const PROTOCOL_VERSION: u32 = 10;
+// Oldest client version the server still understands. Frames are bincode, which has no optional
+// fields: commands and responses added at the end of their enums leave older clients working, but
+// any change to an existing type, such as a new `Task` field, makes them undecodable and this has to
+// follow `PROTOCOL_VERSION`. So the check is a hard gate rather than a compatibility range, client
+// and server are deployed together whenever `Task` changes. Version 10 added `blocked_by` to `Task`.
+pub const MIN_PROTOCOL_VERSION: u32 = 10;
+// Optional behaviour a peer supports. Sent by name so a peer simply ignores the ones it doesn't know.
+pub const FEATURES: &[&str] = &["atomic-requests", "structured-errors", "idempotency-keys", "shutdown-notice", "health"];
+
+// Guards against peers that skip the handshake, whose first frame could otherwise decode as a `Hello`.
+const HELLO_MAGIC: u32 = u32::from_be_bytes(*b"TODO");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    magic: u32,
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            magic: HELLO_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    // Server side check of a client's hello, the error is the rejection reason.
+    pub fn check(&self) -> Result<(), String> {
+        if self.magic != HELLO_MAGIC {
+            return Err("Expected a handshake, the client is probably too old".to_string());
+        }
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version) {
+            return Err(format!(
+                "Client speaks protocol version {}, the server supports versions {} to {}",
+                self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum HelloResponse {
+    // Carries the server's own hello, so the client knows what it may use.
+    Accepted(Hello),
+    Rejected{server: Hello, reason: String},
 }
@@ -0,0 +1,37 @@
+use chrono::{NaiveDate, TimeDelta, Utc};
+use net::{Priority, Task};
+
+fn task(title: &str) -> Task {
+    Task {
+        title: title.to_string(),
+        priority: Priority::Regular,
+        completed: false,
+        id: 1,
+        created_at: NaiveDate::from_ymd_opt(2025, 8, 20).unwrap().and_hms_opt(12, 0, 0).unwrap(),
+        completed_at: None,
+        archived_at: None,
+        due_at: None,
+        tags: Vec::new(),
+        project_id: None,
+        parent_id: None,
+        blocked_by: Vec::new(),
+    }
+}
+
+#[test]
+fn tags_blockers_and_dates_follow_the_title() {
+    assert_eq!(task("plain").format(), "[Regular]: plain");
+
+    let tagged = Task { tags: vec!["home".to_string(), "urgent".to_string()], ..task("tagged") };
+    assert_eq!(tagged.format(), "[Regular]: tagged #home #urgent");
+
+    let due = Task { due_at: Some(Utc::now().naive_utc() + TimeDelta::days(3)), ..task("due") };
+    assert_eq!(due.format(), "[Regular]: due (due in 3 days)");
+
+    let done_at = NaiveDate::from_ymd_opt(2025, 8, 21).unwrap().and_hms_opt(9, 30, 0).unwrap();
+    let done = Task { completed: true, completed_at: Some(done_at), due_at: Some(done_at), ..task("done") };
+    assert_eq!(done.format(), "[Regular]: done (done 2025-08-21 09:30)");
+
+    let blocked = Task { tags: vec!["home".to_string()], blocked_by: vec![2, 5], ..task("blocked") };
+    assert_eq!(blocked.format(), "[Regular]: blocked #home [blocked by 2, 5]");
+}
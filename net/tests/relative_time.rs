@@ -0,0 +1,16 @@
+use chrono::{NaiveDate, TimeDelta};
+use net::relative_time;
+
+#[test]
+fn rounds_to_the_largest_whole_unit() {
+    let now = NaiveDate::from_ymd_opt(2025, 8, 20).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let at = |delta: TimeDelta| relative_time(now + delta, now);
+
+    assert_eq!(at(TimeDelta::seconds(10)), "now");
+    assert_eq!(at(TimeDelta::minutes(1)), "in 1 minute");
+    assert_eq!(at(TimeDelta::minutes(-45)), "45 minutes ago");
+    assert_eq!(at(TimeDelta::minutes(100)), "in 2 hours");
+    assert_eq!(at(TimeDelta::hours(-30)), "30 hours ago");
+    assert_eq!(at(TimeDelta::days(3) - TimeDelta::seconds(1)), "in 3 days");
+    assert_eq!(at(TimeDelta::days(-10)), "10 days ago");
+}
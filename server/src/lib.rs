@@ -1,104 +1,857 @@
-use sqlx::{PgPool, Error as DbError};
-use net::{Task, Priority};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Connection as _, PgConnection, PgPool, Postgres, Error as DbError};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::error::ErrorKind as DbErrorKind;
+use sqlx::migrate::Migrator;
+use tracing::instrument;
+use net::{Task, Priority, HealthCheck, Project, TagMatch, TaskTree};
+use task_store::{PoolConfig, PoolStatus, TaskStore, Error as StoreError};
+use transaction::{Connection, SharedTransaction};
+
+mod sqlite;
+mod transaction;
+
+pub use sqlite::TaskSqliteDatabase;
+
+// Applied with `sqlx migrate run`, the server only checks they were.
+static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
 
 #[derive(Clone)]
 pub struct TaskPgDatabase {
-    pool: PgPool
+    pool: PgPool,
+    transaction: Option<SharedTransaction<Postgres>>,
+}
+
+// Shared by the Postgres and SQLite backends. Constraint violations are the client's doing, so
+// they keep the constraint name; connection trouble is reported apart from other failures.
+fn store_error(e: DbError) -> StoreError {
+    match e {
+        DbError::RowNotFound => StoreError::NotFound,
+        DbError::Database(db_error) => {
+            let constraint = db_error.constraint().unwrap_or("unnamed constraint").to_string();
+            match db_error.kind() {
+                DbErrorKind::UniqueViolation => StoreError::Conflict(format!("duplicate value violates {}", constraint)),
+                DbErrorKind::ForeignKeyViolation => StoreError::Conflict(format!("reference violates {}", constraint)),
+                DbErrorKind::NotNullViolation | DbErrorKind::CheckViolation => {
+                    StoreError::Validation(format!("value violates {}", constraint))
+                },
+                // insufficient_privilege
+                _ if db_error.code().as_deref() == Some("42501") => StoreError::Unauthorized(db_error.message().to_string()),
+                _ => StoreError::database(DbError::Database(db_error)),
+            }
+        },
+        DbError::PoolTimedOut | DbError::PoolClosed | DbError::Io(_) => StoreError::unavailable(e),
+        e => StoreError::database(e),
+    }
+}
+
+// Shared by the Postgres and SQLite backends, for statements setting a task's project. The only
+// reference they can break is the one to the project.
+fn project_reference_error(e: DbError) -> StoreError {
+    match e {
+        DbError::Database(db_error) if matches!(db_error.kind(), DbErrorKind::ForeignKeyViolation) => StoreError::ProjectNotFound,
+        e => store_error(e),
+    }
+}
+
+// Shared by the Postgres and SQLite backends, for statements adding a dependency. Both of its ends
+// are tasks.
+fn task_reference_error(e: DbError) -> StoreError {
+    match e {
+        DbError::Database(db_error) if matches!(db_error.kind(), DbErrorKind::ForeignKeyViolation) => StoreError::NotFound,
+        e => store_error(e),
+    }
+}
+
+// Serializes changes to one of the task hierarchies, the subtasks or the dependencies, so a cycle
+// check still holds when its write lands. Held until the outermost transaction ends.
+async fn lock_hierarchy(conn: &mut PgConnection, hierarchy: &str) -> Result<(), StoreError> {
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext($1));", hierarchy)
+        .execute(conn)
+        .await
+        .map_err(store_error)?;
+    Ok(())
+}
+
+// Shared by the Postgres and SQLite backends, for statements naming a project.
+fn project_name_error(e: DbError, name: &str) -> StoreError {
+    match e {
+        DbError::Database(db_error) if matches!(db_error.kind(), DbErrorKind::UniqueViolation) => {
+            StoreError::Conflict(format!("a project named {:?} already exists", name))
+        },
+        e => store_error(e),
+    }
+}
+
+// Shared by the Postgres and SQLite backends. `applied` are the versions `_sqlx_migrations`
+// records as successfully applied, every migration built into the binary should be among them.
+fn migrations_check(migrator: &Migrator, applied: Result<Vec<i64>, DbError>) -> HealthCheck {
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(e) => return HealthCheck::new("migrations", false).with_details(&format!("can't read applied migrations: {}", e)),
+    };
+    let pending = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration() && !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        HealthCheck::new("migrations", true)
+    } else {
+        HealthCheck::new("migrations", false).with_details(&format!("not applied: {}", pending.join(", ")))
+    }
 }
 
 impl TaskPgDatabase {
 
-    pub async fn connect(url: &str) -> Result<Self, DbError> {
-        let pool = PgPool::connect(&url).await?;
-        Ok(Self{pool})
+    pub async fn connect(url: &str, pool: PoolConfig) -> Result<Self, DbError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool.max_connections)
+            .acquire_timeout(pool.timeout)
+            .connect(url)
+            .await?;
+        Ok(Self{pool, transaction: None})
+    }
+
+    // Waits for checked out connections to come back, then closes them all.
+    pub async fn close(&self) {
+        self.pool.close().await;
     }
 
-    pub async fn new_task(&self, title: &str, priority: Priority) -> Result<Task, DbError> {
+    async fn connection(&self) -> Result<Connection<'_, Postgres>, StoreError> {
+        transaction::acquire(&self.pool, &self.transaction).await
+    }
+
+}
+
+impl TaskStore for TaskPgDatabase {
+
+    type Id = i32;
+    type Task = Task;
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn begin(&self) -> Result<Self, StoreError> {
+        let transaction = transaction::begin(&self.pool, &self.transaction).await?;
+        Ok(Self{pool: self.pool.clone(), transaction: Some(transaction)})
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn commit(self) -> Result<(), StoreError> {
+        transaction::finish(self.transaction, true).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rollback(self) -> Result<(), StoreError> {
+        transaction::finish(self.transaction, false).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_task(&self, title: &str, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
         let task = sqlx::query_as!(Task,
             r#"
-            INSERT INTO tasks (title, priority)
-            VALUES ($1, $2)
-            RETURNING id, title, priority as "priority: Priority", completed, created_at;
+            INSERT INTO tasks (title, priority, due_at, project_id)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, title, priority as "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
             "#,
             title,
-            priority as Priority)
-            .fetch_one(&self.pool)
-            .await?;
+            priority as Priority,
+            due_at,
+            project_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(project_reference_error)?;
         Ok(task)
     }
 
-    pub async fn pending_tasks(&self) -> Result<Vec<Task>, DbError> {
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn pending_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
         let pending_tasks = sqlx::query_as!(Task,
             r#"
-            SELECT id, title, priority AS "priority: Priority", completed, created_at FROM tasks
-            WHERE completed = $1;
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE completed = $1 AND archived_at IS NULL;
             "#,
             false)
-            .fetch_all(&self.pool)
-            .await?;
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
         Ok(pending_tasks)
     }
 
-    pub async fn done_tasks(&self) -> Result<Vec<Task>, DbError> {
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn done_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
         let done_tasks = sqlx::query_as!(Task,
             r#"
-            SELECT id, title, priority AS "priority: Priority", completed, created_at FROM tasks
-            WHERE completed = $1;
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE completed = $1 AND archived_at IS NULL;
             "#,
             true)
-            .fetch_all(&self.pool)
-            .await?;
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
         Ok(done_tasks)
     }
 
-    pub async fn mark_task_done(&self, task_id: i32) -> Result<(), DbError> {
-        sqlx::query!(r#"
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn mark_task_done(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        // The checks are part of the update, so a subtask or blocker added in between can't slip by
+        let task = sqlx::query_as!(Task, r#"
         UPDATE tasks
-        SET completed = $1
-        WHERE id = $2;
+        SET completed = $1, completed_at = COALESCE(completed_at, CURRENT_TIMESTAMP)
+        WHERE id = $2
+        AND NOT EXISTS (SELECT 1 FROM tasks AS subtasks WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL)
+        AND NOT EXISTS (
+            SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+            WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+        )
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
         "#,
         true,
         task_id
         )
-        .execute(&self.pool)
-        .await?;
-        Ok(())
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        if let Some(task) = task {
+            return Ok(task);
+        }
+        // Nothing was updated, either there is no such task or one of the checks failed
+        let open_subtasks = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM tasks AS subtasks WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL) AS "open_subtasks!"
+            FROM tasks WHERE id = $1;
+            "#,
+            task_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        match open_subtasks {
+            None => Err(StoreError::NotFound),
+            Some(true) => Err(StoreError::Conflict("the task still has open subtasks".to_string())),
+            Some(false) => Err(StoreError::Conflict("the task is still blocked by open tasks".to_string())),
+        }
     }
 
-    pub async fn edit_task_title(&self, task_id: i32, title: &str) -> Result<(), DbError> {
-        sqlx::query!(r#"
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn reopen_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET completed = $1, completed_at = NULL
+        WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        false,
+        task_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_title(&self, task_id: i32, title: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
         UPDATE tasks
         SET title = $1
-        WHERE id = $2;
+        WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
         "#,
         title,
         task_id)
-        .execute(&self.pool)
-        .await?;
-        Ok(())
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
     }
 
-    pub async fn edit_task_priority(&self, task_id: i32, priority: Priority) -> Result<(), DbError> {
-        sqlx::query!(r#"
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_priority(&self, task_id: i32, priority: Priority) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
         UPDATE tasks
         SET priority = $1
         WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
         "#,
         priority as Priority,
         task_id)
-        .execute(&self.pool)
-        .await?;
-        Ok(())
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
     }
 
-    pub async fn query_task_by_id(&self, task_id: i32) -> Result<Task, DbError> {
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn query_task_by_id(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
         let task = sqlx::query_as!(Task, r#"
-        SELECT id, created_at, title, completed, priority AS "priority: Priority" FROM tasks
+        SELECT id, created_at, completed_at, title, completed, priority AS "priority: Priority", archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
         WHERE id = $1;"#,
         task_id)
-            .fetch_one(&self.pool)
-            .await?;
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        DELETE FROM tasks
+        WHERE id = $1
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        task_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archive_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET archived_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        task_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn unarchive_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET archived_at = NULL
+        WHERE id = $1
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        task_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archived_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let archived_tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE archived_at IS NOT NULL;
+            "#)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(archived_tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn set_due_date(&self, task_id: i32, due_at: Option<NaiveDateTime>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET due_at = $1
+        WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        due_at,
+        task_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(store_error)?;
+        Ok(task)
+    }
+
+    // Both due date queries match the conditions of the partial index on `due_at`.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn overdue_tasks(&self, now: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let overdue_tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND due_at IS NOT NULL AND due_at < $1
+            ORDER BY due_at;
+            "#,
+            now)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(overdue_tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn due_tasks(&self, from: NaiveDateTime, until: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let due_tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND due_at IS NOT NULL AND due_at BETWEEN $1 AND $2
+            ORDER BY due_at;
+            "#,
+            from,
+            until)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(due_tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_tag(&self, task_id: i32, tag: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query!(
+            "INSERT INTO task_tags (task_id, tag) SELECT id, $2 FROM tasks WHERE id = $1 ON CONFLICT DO NOTHING;",
+            task_id, tag)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        // Read back separately, the insert isn't visible to a query in the same statement
+        let task = sqlx::query_as!(Task, r#"
+        SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+        WHERE id = $1;"#,
+        task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_tag(&self, task_id: i32, tag: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query!("DELETE FROM task_tags WHERE task_id = $1 AND tag = $2;", task_id, tag)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let task = sqlx::query_as!(Task, r#"
+        SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+        WHERE id = $1;"#,
+        task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
         Ok(task)
     }
 
+    // Tasks with all the tags have one `task_tags` row per tag, as `tags` has no duplicates.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_with_tag(&self, tags: &[String], matching: TagMatch) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE archived_at IS NULL AND id IN (
+                SELECT task_id FROM task_tags WHERE tag = ANY($1)
+                GROUP BY task_id
+                HAVING $2 OR COUNT(*) = cardinality($1)
+            )
+            ORDER BY id;
+            "#,
+            tags,
+            matching == TagMatch::Any)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_project(&self, name: &str) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as!(Project,
+            "INSERT INTO projects (name) VALUES ($1) RETURNING id, name, created_at;",
+            name)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| project_name_error(e, name))?;
+        Ok(project)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rename_project(&self, project_id: i32, name: &str) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as!(Project,
+            "UPDATE projects SET name = $1 WHERE id = $2 RETURNING id, name, created_at;",
+            name, project_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|e| project_name_error(e, name))?;
+        project.ok_or(StoreError::ProjectNotFound)
+    }
+
+    // `ON DELETE SET NULL` takes the tasks out of the project.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_project(&self, project_id: i32) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as!(Project,
+            "DELETE FROM projects WHERE id = $1 RETURNING id, name, created_at;",
+            project_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        project.ok_or(StoreError::ProjectNotFound)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn projects(&self) -> Result<Vec<Project>, StoreError> {
+        let mut conn = self.connection().await?;
+        let projects = sqlx::query_as!(Project, "SELECT id, name, created_at FROM projects ORDER BY id;")
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(projects)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_task(&self, task_id: i32, project_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET project_id = $1
+        WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        project_id,
+        task_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(project_reference_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn project_tasks(&self, project_id: i32, completed: bool) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE project_id = $1 AND completed = $2 AND archived_at IS NULL
+            ORDER BY id;
+            "#,
+            project_id,
+            completed)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_subtask(&self, parent_id: i32, title: &str, priority: Priority) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as!(Task,
+            r#"
+            INSERT INTO tasks (title, priority, project_id, parent_id)
+            SELECT $1, $2, project_id, id FROM tasks WHERE id = $3
+            RETURNING id, title, priority as "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+            "#,
+            title,
+            priority as Priority,
+            parent_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    // `UNION` rather than `UNION ALL` in the recursive queries, so they end even if a cycle got in.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_subtask(&self, task_id: i32, parent_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        // A savepoint if a transaction is already open
+        let mut tx = conn.begin().await.map_err(store_error)?;
+        lock_hierarchy(&mut tx, "tasks.parent_id").await?;
+        if let Some(parent_id) = parent_id {
+            let ancestors = sqlx::query_scalar!(
+                r#"
+                WITH RECURSIVE ancestors (id, parent_id) AS (
+                    SELECT id, parent_id FROM tasks WHERE id = $1
+                    UNION
+                    SELECT tasks.id, tasks.parent_id FROM tasks JOIN ancestors ON tasks.id = ancestors.parent_id
+                )
+                SELECT id AS "id!" FROM ancestors;
+                "#,
+                parent_id)
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(store_error)?;
+            if ancestors.is_empty() {
+                return Err(StoreError::NotFound);
+            }
+            if ancestors.contains(&task_id) {
+                return Err(StoreError::Validation("A task can't be moved below itself or one of its subtasks".to_string()));
+            }
+        }
+        let task = sqlx::query_as!(Task, r#"
+        UPDATE tasks
+        SET parent_id = $1
+        WHERE id = $2
+        RETURNING id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!";
+        "#,
+        parent_id,
+        task_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(store_error)?;
+        tx.commit().await.map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn task_tree(&self, task_id: i32) -> Result<TaskTree<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let mut tasks = sqlx::query_as!(Task,
+            r#"
+            WITH RECURSIVE tree (id) AS (
+                SELECT id FROM tasks WHERE id = $1
+                UNION
+                SELECT tasks.id FROM tasks JOIN tree ON tasks.parent_id = tree.id
+            )
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE id IN (SELECT id FROM tree)
+            ORDER BY id;
+            "#,
+            task_id)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let root = tasks.iter().position(|task| task.id == task_id).ok_or(StoreError::NotFound)?;
+        let root = tasks.remove(root);
+        Ok(TaskTree::build(root, tasks, |task| task.id, |task| task.parent_id))
+    }
+
+    // One level per statement, each completing the parent only while it has no open subtask left and
+    // no open task blocking it.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn complete_finished_parents(&self, task_id: i32) -> Result<(), StoreError> {
+        let mut conn = self.connection().await?;
+        let mut current = task_id;
+        loop {
+            let completed = sqlx::query_scalar!(
+                r#"
+                UPDATE tasks
+                SET completed = TRUE, completed_at = COALESCE(completed_at, CURRENT_TIMESTAMP)
+                WHERE id = (SELECT parent_id FROM tasks WHERE id = $1) AND NOT completed AND NOT EXISTS (
+                    SELECT 1 FROM tasks AS subtasks
+                    WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+                    WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+                )
+                RETURNING id;
+                "#,
+                current)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(store_error)?;
+            match completed {
+                Some(parent_id) => current = parent_id,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let mut tx = conn.begin().await.map_err(store_error)?;
+        lock_hierarchy(&mut tx, "task_dependencies").await?;
+        // Everything `blocked_by` waits on, itself included; the task must not be among it
+        let cycle = sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE blockers (id) AS (
+                SELECT $1::INTEGER
+                UNION
+                SELECT task_dependencies.blocker_id FROM task_dependencies JOIN blockers ON task_dependencies.blocked_id = blockers.id
+            )
+            SELECT EXISTS (SELECT 1 FROM blockers WHERE id = $2) AS "exists!";
+            "#,
+            blocked_by,
+            task_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        if cycle {
+            return Err(StoreError::Validation("A task can't wait on itself, directly or through other tasks".to_string()));
+        }
+        sqlx::query!(
+            "INSERT INTO task_dependencies (blocker_id, blocked_id) VALUES ($1, $2) ON CONFLICT DO NOTHING;",
+            blocked_by,
+            task_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(task_reference_error)?;
+        let task = sqlx::query_as!(Task, r#"
+        SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+        WHERE id = $1;"#,
+        task_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        tx.commit().await.map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query!("DELETE FROM task_dependencies WHERE blocked_id = $1 AND blocker_id = $2;", task_id, blocked_by)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let task = sqlx::query_as!(Task, r#"
+        SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+        ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+        ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+        WHERE id = $1;"#,
+        task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_by_readiness(&self, ready: bool) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as!(Task,
+            r#"
+            SELECT id, title, priority AS "priority: Priority", completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+            ARRAY(SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag) AS "tags!",
+            ARRAY(SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id) AS "blocked_by!" FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND EXISTS (
+                SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+                WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+            ) <> $1
+            ORDER BY id;
+            "#,
+            ready)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn saved_response(&self, key: &str, since: NaiveDateTime) -> Result<Option<Vec<u8>>, StoreError> {
+        let mut conn = self.connection().await?;
+        let response = sqlx::query_scalar!(
+            "SELECT response FROM idempotency_keys WHERE key = $1 AND created_at > $2;",
+            key, since)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(response)
+    }
+
+    #[instrument(level = "debug", skip(self, response), err(level = "debug"))]
+    async fn save_response(&self, key: &str, response: &[u8], expired_before: NaiveDateTime) -> Result<(), StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query!("DELETE FROM idempotency_keys WHERE created_at < $1;", expired_before)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        sqlx::query!(
+            r#"
+            INSERT INTO idempotency_keys (key, response, created_at) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE SET response = EXCLUDED.response, created_at = EXCLUDED.created_at;
+            "#,
+            key, response, Utc::now().naive_utc())
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    // Runs on a connection of its own even inside a transaction, a failing check must not abort it.
+    #[instrument(level = "debug", skip(self))]
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let mut conn = match self.pool.acquire().await {
+            Ok(conn) => conn,
+            Err(e) => return vec![HealthCheck::new("database", false).with_details(&e.to_string())],
+        };
+        if let Err(e) = sqlx::query("SELECT 1").execute(&mut *conn).await {
+            return vec![HealthCheck::new("database", false).with_details(&e.to_string())];
+        }
+        let applied = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(&mut *conn)
+            .await;
+        vec![
+            HealthCheck::new("database", true),
+            migrations_check(&MIGRATOR, applied),
+        ]
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            open: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max: self.pool.options().get_max_connections(),
+        })
+    }
+
 }
@@ -0,0 +1,674 @@
+use std::str::FromStr;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Connection as _, SqliteConnection, SqlitePool, Sqlite, Transaction, Error as DbError};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use tracing::instrument;
+use net::{Task, Priority, HealthCheck, Project, TagMatch, TaskTree};
+use task_store::{PoolConfig, PoolStatus, TaskStore, Error as StoreError};
+use crate::{migrations_check, project_name_error, project_reference_error, store_error, task_reference_error};
+use crate::transaction::{self, Connection, SharedTransaction};
+
+// The compile-time checked `query!` macros are bound to the Postgres `DATABASE_URL`, so this
+// backend uses the runtime checked query functions instead.
+const TASK_COLUMNS: &str = "id, title, priority, completed, created_at, completed_at, archived_at, due_at, project_id, parent_id,
+    (SELECT json_group_array(tag) FROM (SELECT tag FROM task_tags WHERE task_id = tasks.id ORDER BY tag)) AS tags,
+    (SELECT json_group_array(blocker_id) FROM (
+        SELECT blocker_id FROM task_dependencies WHERE blocked_id = tasks.id ORDER BY blocker_id
+    )) AS blocked_by";
+
+static MIGRATOR: Migrator = sqlx::migrate!("../migrations-sqlite");
+
+// Takes the write lock before anything is read, so a cycle check still holds when its write lands.
+// Inside an open transaction this is a savepoint: if another writer got in since that transaction
+// first read, SQLite refuses its write rather than letting it through.
+async fn begin_immediate(conn: &mut SqliteConnection) -> Result<Transaction<'_, Sqlite>, StoreError> {
+    let tx = if conn.is_in_transaction() {
+        conn.begin().await
+    } else {
+        conn.begin_with("BEGIN IMMEDIATE").await
+    };
+    tx.map_err(store_error)
+}
+
+#[derive(Clone)]
+pub struct TaskSqliteDatabase {
+    pool: SqlitePool,
+    transaction: Option<SharedTransaction<Sqlite>>,
+}
+
+impl TaskSqliteDatabase {
+
+    // Opens (creating it if needed) the database file and applies `migrations-sqlite/`.
+    pub async fn connect(url: &str, pool: PoolConfig) -> Result<Self, DbError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(pool.max_connections)
+            .acquire_timeout(pool.timeout)
+            .connect_with(options)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(Self{pool, transaction: None})
+    }
+
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn connection(&self) -> Result<Connection<'_, Sqlite>, StoreError> {
+        transaction::acquire(&self.pool, &self.transaction).await
+    }
+
+    async fn tasks_by_completion(&self, completed: bool) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE completed = ? AND archived_at IS NULL;"))
+            .bind(completed)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+}
+
+impl TaskStore for TaskSqliteDatabase {
+
+    type Id = i32;
+    type Task = Task;
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn begin(&self) -> Result<Self, StoreError> {
+        let transaction = transaction::begin(&self.pool, &self.transaction).await?;
+        Ok(Self{pool: self.pool.clone(), transaction: Some(transaction)})
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn commit(self) -> Result<(), StoreError> {
+        transaction::finish(self.transaction, true).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rollback(self) -> Result<(), StoreError> {
+        transaction::finish(self.transaction, false).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_task(&self, title: &str, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "INSERT INTO tasks (title, priority, due_at, project_id) VALUES (?, ?, ?, ?) RETURNING {TASK_COLUMNS};"))
+            .bind(title)
+            .bind(priority)
+            .bind(due_at)
+            .bind(project_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(project_reference_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn pending_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        self.tasks_by_completion(false).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn done_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        self.tasks_by_completion(true).await
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn mark_task_done(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        // The checks are part of the update, so a subtask or blocker added in between can't slip by
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET completed = ?, completed_at = COALESCE(completed_at, CURRENT_TIMESTAMP)
+            WHERE id = ?
+            AND NOT EXISTS (SELECT 1 FROM tasks AS subtasks WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL)
+            AND NOT EXISTS (
+                SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+                WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+            )
+            RETURNING {TASK_COLUMNS};"))
+            .bind(true)
+            .bind(task_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        if let Some(task) = task {
+            return Ok(task);
+        }
+        // Nothing was updated, either there is no such task or one of the checks failed
+        let open_subtasks = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM tasks AS subtasks WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL)
+            FROM tasks WHERE id = ?;")
+            .bind(task_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        match open_subtasks {
+            None => Err(StoreError::NotFound),
+            Some(true) => Err(StoreError::Conflict("the task still has open subtasks".to_string())),
+            Some(false) => Err(StoreError::Conflict("the task is still blocked by open tasks".to_string())),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn reopen_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET completed = ?, completed_at = NULL WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(false)
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_title(&self, task_id: i32, title: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET title = ? WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(title)
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn edit_task_priority(&self, task_id: i32, priority: Priority) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET priority = ? WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(priority)
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn query_task_by_id(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        // Read first, SQLite has already deleted the tags by the time `RETURNING` is evaluated
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        sqlx::query("DELETE FROM tasks WHERE id = ?;")
+            .bind(task_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archive_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET archived_at = CURRENT_TIMESTAMP WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn unarchive_task(&self, task_id: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET archived_at = NULL WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn archived_tasks(&self) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE archived_at IS NOT NULL;"))
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn set_due_date(&self, task_id: i32, due_at: Option<NaiveDateTime>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET due_at = ? WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(due_at)
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    // Both due date queries match the conditions of the partial index on `due_at`.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn overdue_tasks(&self, now: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND due_at IS NOT NULL AND due_at < ?
+            ORDER BY due_at;"))
+            .bind(now)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn due_tasks(&self, from: NaiveDateTime, until: NaiveDateTime) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND due_at IS NOT NULL AND due_at BETWEEN ? AND ?
+            ORDER BY due_at;"))
+            .bind(from)
+            .bind(until)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_tag(&self, task_id: i32, tag: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query("INSERT INTO task_tags (task_id, tag) SELECT id, ? FROM tasks WHERE id = ? ON CONFLICT DO NOTHING;")
+            .bind(tag)
+            .bind(task_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_tag(&self, task_id: i32, tag: &str) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query("DELETE FROM task_tags WHERE task_id = ? AND tag = ?;")
+            .bind(task_id)
+            .bind(tag)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    // Tasks with all the tags have one `task_tags` row per tag, as `tags` has no duplicates.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_with_tag(&self, tags: &[String], matching: TagMatch) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let placeholders = vec!["?"; tags.len()].join(", ");
+        let sql = format!(
+            "SELECT {TASK_COLUMNS} FROM tasks
+            WHERE archived_at IS NULL AND id IN (
+                SELECT task_id FROM task_tags WHERE tag IN ({placeholders})
+                GROUP BY task_id
+                HAVING ? OR COUNT(*) = ?
+            )
+            ORDER BY id;");
+        let mut query = sqlx::query_as::<_, Task>(&sql);
+        for tag in tags {
+            query = query.bind(tag);
+        }
+        let tasks = query
+            .bind(matching == TagMatch::Any)
+            .bind(tags.len() as i64)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn new_project(&self, name: &str) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as::<_, Project>(
+            "INSERT INTO projects (name) VALUES (?) RETURNING id, name, created_at;")
+            .bind(name)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| project_name_error(e, name))?;
+        Ok(project)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn rename_project(&self, project_id: i32, name: &str) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as::<_, Project>(
+            "UPDATE projects SET name = ? WHERE id = ? RETURNING id, name, created_at;")
+            .bind(name)
+            .bind(project_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|e| project_name_error(e, name))?;
+        project.ok_or(StoreError::ProjectNotFound)
+    }
+
+    // `ON DELETE SET NULL` takes the tasks out of the project.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn delete_project(&self, project_id: i32) -> Result<Project, StoreError> {
+        let mut conn = self.connection().await?;
+        let project = sqlx::query_as::<_, Project>(
+            "DELETE FROM projects WHERE id = ? RETURNING id, name, created_at;")
+            .bind(project_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        project.ok_or(StoreError::ProjectNotFound)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn projects(&self) -> Result<Vec<Project>, StoreError> {
+        let mut conn = self.connection().await?;
+        let projects = sqlx::query_as::<_, Project>("SELECT id, name, created_at FROM projects ORDER BY id;")
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(projects)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_task(&self, task_id: i32, project_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET project_id = ? WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(project_id)
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(project_reference_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn project_tasks(&self, project_id: i32, completed: bool) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks
+            WHERE project_id = ? AND completed = ? AND archived_at IS NULL
+            ORDER BY id;"))
+            .bind(project_id)
+            .bind(completed)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_subtask(&self, parent_id: i32, title: &str, priority: Priority) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "INSERT INTO tasks (title, priority, project_id, parent_id)
+            SELECT ?, ?, project_id, id FROM tasks WHERE id = ?
+            RETURNING {TASK_COLUMNS};"))
+            .bind(title)
+            .bind(priority)
+            .bind(parent_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    // `UNION` rather than `UNION ALL` in the recursive queries, so they end even if a cycle got in.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn move_subtask(&self, task_id: i32, parent_id: Option<i32>) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let mut tx = begin_immediate(&mut conn).await?;
+        if let Some(parent_id) = parent_id {
+            let ancestors = sqlx::query_scalar::<_, i32>(
+                "WITH RECURSIVE ancestors (id, parent_id) AS (
+                    SELECT id, parent_id FROM tasks WHERE id = ?
+                    UNION
+                    SELECT tasks.id, tasks.parent_id FROM tasks JOIN ancestors ON tasks.id = ancestors.parent_id
+                )
+                SELECT id FROM ancestors;")
+                .bind(parent_id)
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(store_error)?;
+            if ancestors.is_empty() {
+                return Err(StoreError::NotFound);
+            }
+            if ancestors.contains(&task_id) {
+                return Err(StoreError::Validation("A task can't be moved below itself or one of its subtasks".to_string()));
+            }
+        }
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "UPDATE tasks SET parent_id = ? WHERE id = ? RETURNING {TASK_COLUMNS};"))
+            .bind(parent_id)
+            .bind(task_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        tx.commit().await.map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn task_tree(&self, task_id: i32) -> Result<TaskTree<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let mut tasks = sqlx::query_as::<_, Task>(&format!(
+            "WITH RECURSIVE tree (id) AS (
+                SELECT id FROM tasks WHERE id = ?
+                UNION
+                SELECT tasks.id FROM tasks JOIN tree ON tasks.parent_id = tree.id
+            )
+            SELECT {TASK_COLUMNS} FROM tasks
+            WHERE id IN (SELECT id FROM tree)
+            ORDER BY id;"))
+            .bind(task_id)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let root = tasks.iter().position(|task| task.id == task_id).ok_or(StoreError::NotFound)?;
+        let root = tasks.remove(root);
+        Ok(TaskTree::build(root, tasks, |task| task.id, |task| task.parent_id))
+    }
+
+    // One level per statement, each completing the parent only while it has no open subtask left and
+    // no open task blocking it.
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn complete_finished_parents(&self, task_id: i32) -> Result<(), StoreError> {
+        let mut conn = self.connection().await?;
+        let mut current = task_id;
+        loop {
+            let completed = sqlx::query_scalar::<_, i32>(
+                "UPDATE tasks
+                SET completed = TRUE, completed_at = COALESCE(completed_at, CURRENT_TIMESTAMP)
+                WHERE id = (SELECT parent_id FROM tasks WHERE id = ?) AND NOT completed AND NOT EXISTS (
+                    SELECT 1 FROM tasks AS subtasks
+                    WHERE subtasks.parent_id = tasks.id AND NOT subtasks.completed AND subtasks.archived_at IS NULL
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+                    WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+                )
+                RETURNING id;")
+                .bind(current)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(store_error)?;
+            match completed {
+                Some(parent_id) => current = parent_id,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn add_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        let mut tx = begin_immediate(&mut conn).await?;
+        // Everything `blocked_by` waits on, itself included; the task must not be among it
+        let cycle = sqlx::query_scalar::<_, bool>(
+            "WITH RECURSIVE blockers (id) AS (
+                SELECT ?
+                UNION
+                SELECT task_dependencies.blocker_id FROM task_dependencies JOIN blockers ON task_dependencies.blocked_id = blockers.id
+            )
+            SELECT EXISTS (SELECT 1 FROM blockers WHERE id = ?);")
+            .bind(blocked_by)
+            .bind(task_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        if cycle {
+            return Err(StoreError::Validation("A task can't wait on itself, directly or through other tasks".to_string()));
+        }
+        sqlx::query("INSERT INTO task_dependencies (blocker_id, blocked_id) VALUES (?, ?) ON CONFLICT DO NOTHING;")
+            .bind(blocked_by)
+            .bind(task_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(task_reference_error)?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        tx.commit().await.map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn remove_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query("DELETE FROM task_dependencies WHERE blocked_id = ? AND blocker_id = ?;")
+            .bind(task_id)
+            .bind(blocked_by)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        let task = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE id = ?;"))
+            .bind(task_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(task)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn tasks_by_readiness(&self, ready: bool) -> Result<Vec<Task>, StoreError> {
+        let mut conn = self.connection().await?;
+        let tasks = sqlx::query_as::<_, Task>(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks
+            WHERE NOT completed AND archived_at IS NULL AND EXISTS (
+                SELECT 1 FROM task_dependencies JOIN tasks AS blockers ON blockers.id = task_dependencies.blocker_id
+                WHERE task_dependencies.blocked_id = tasks.id AND NOT blockers.completed AND blockers.archived_at IS NULL
+            ) <> ?
+            ORDER BY id;"))
+            .bind(ready)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(tasks)
+    }
+
+    #[instrument(level = "debug", skip(self), err(level = "debug"))]
+    async fn saved_response(&self, key: &str, since: NaiveDateTime) -> Result<Option<Vec<u8>>, StoreError> {
+        let mut conn = self.connection().await?;
+        let response = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT response FROM idempotency_keys WHERE key = ? AND created_at > ?;")
+            .bind(key)
+            .bind(since)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(response)
+    }
+
+    #[instrument(level = "debug", skip(self, response), err(level = "debug"))]
+    async fn save_response(&self, key: &str, response: &[u8], expired_before: NaiveDateTime) -> Result<(), StoreError> {
+        let mut conn = self.connection().await?;
+        sqlx::query("DELETE FROM idempotency_keys WHERE created_at < ?;")
+            .bind(expired_before)
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        sqlx::query(
+            "INSERT INTO idempotency_keys (key, response, created_at) VALUES (?, ?, ?)
+            ON CONFLICT (key) DO UPDATE SET response = excluded.response, created_at = excluded.created_at;")
+            .bind(key)
+            .bind(response)
+            .bind(Utc::now().naive_utc())
+            .execute(&mut *conn)
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    // Migrations are applied by `connect`, so failing that check means someone else changed the file.
+    #[instrument(level = "debug", skip(self))]
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let mut conn = match self.pool.acquire().await {
+            Ok(conn) => conn,
+            Err(e) => return vec![HealthCheck::new("database", false).with_details(&e.to_string())],
+        };
+        if let Err(e) = sqlx::query("SELECT 1").execute(&mut *conn).await {
+            return vec![HealthCheck::new("database", false).with_details(&e.to_string())];
+        }
+        let applied = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(&mut *conn)
+            .await;
+        vec![
+            HealthCheck::new("database", true),
+            migrations_check(&MIGRATOR, applied),
+        ]
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            open: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max: self.pool.options().get_max_connections(),
+        })
+    }
+
+}
@@ -0,0 +1,74 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use sqlx::{Database, Pool, Transaction};
+use sqlx::pool::PoolConnection;
+use task_store::Error as StoreError;
+use crate::store_error;
+
+pub(crate) type SharedTransaction<DB> = Arc<Mutex<Transaction<'static, DB>>>;
+
+// Connection an operation runs on: a fresh one from the pool, or the one owning the open transaction.
+pub(crate) enum Connection<'a, DB: Database> {
+    Pool(PoolConnection<DB>),
+    Transaction(MutexGuard<'a, Transaction<'static, DB>>),
+}
+
+impl<DB: Database> Deref for Connection<'_, DB> {
+    type Target = DB::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Connection::Pool(conn) => conn,
+            Connection::Transaction(tx) => tx,
+        }
+    }
+}
+
+impl<DB: Database> DerefMut for Connection<'_, DB> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Connection::Pool(conn) => conn,
+            Connection::Transaction(tx) => tx,
+        }
+    }
+}
+
+pub(crate) async fn acquire<'a, DB: Database>(
+    pool: &Pool<DB>,
+    transaction: &'a Option<SharedTransaction<DB>>
+) -> Result<Connection<'a, DB>, StoreError> {
+    match transaction {
+        Some(tx) => Ok(Connection::Transaction(tx.lock().await)),
+        None => Ok(Connection::Pool(pool.acquire().await.map_err(store_error)?)),
+    }
+}
+
+pub(crate) async fn begin<DB: Database>(
+    pool: &Pool<DB>,
+    transaction: &Option<SharedTransaction<DB>>
+) -> Result<SharedTransaction<DB>, StoreError> {
+    if transaction.is_some() {
+        return Err(StoreError::Transaction("a transaction is already open".to_string()));
+    }
+    let tx = pool.begin().await.map_err(store_error)?;
+    Ok(Arc::new(Mutex::new(tx)))
+}
+
+// Commits or rolls back the transaction, if there is one.
+pub(crate) async fn finish<DB: Database>(
+    transaction: Option<SharedTransaction<DB>>,
+    commit: bool
+) -> Result<(), StoreError> {
+    let Some(tx) = transaction else {
+        return Ok(());
+    };
+    let tx = Arc::try_unwrap(tx)
+        .map_err(|_| StoreError::Transaction("transaction is still in use".to_string()))?
+        .into_inner();
+    if commit {
+        tx.commit().await.map_err(store_error)
+    } else {
+        tx.rollback().await.map_err(store_error)
+    }
+}
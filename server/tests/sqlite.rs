@@ -0,0 +1,106 @@
+use net::{Priority, TagMatch};
+use task_store::{Error, PoolConfig, TaskStore};
+use todo_app_server::TaskSqliteDatabase;
+
+// Every connection to `sqlite::memory:` opens a database of its own, so the pool keeps just one.
+async fn database() -> TaskSqliteDatabase {
+    let pool = PoolConfig { max_connections: 1, ..PoolConfig::default() };
+    TaskSqliteDatabase::connect("sqlite::memory:", pool).await.unwrap()
+}
+
+#[tokio::test]
+async fn tags_and_blockers_come_back_sorted() {
+    let db = database().await;
+    let task = db.new_task("write docs", Priority::Regular, None, None).await.unwrap();
+    assert!(task.tags.is_empty() && task.blocked_by.is_empty());
+    assert!(!task.completed);
+
+    db.add_tag(task.id, "ops").await.unwrap();
+    let task = db.add_tag(task.id, "docs").await.unwrap();
+    assert_eq!(task.tags, ["docs", "ops"]);
+    let b = db.new_task("b", Priority::Low, None, None).await.unwrap();
+    let a = db.new_task("a", Priority::Low, None, None).await.unwrap();
+    db.add_dependency(task.id, a.id).await.unwrap();
+    let task = db.add_dependency(task.id, b.id).await.unwrap();
+    assert_eq!(task.blocked_by, [b.id, a.id]);
+
+    let tagged = db.tasks_with_tag(&["ops".to_string(), "backend".to_string()], TagMatch::Any).await.unwrap();
+    assert_eq!(tagged.iter().map(|task| task.id).collect::<Vec<_>>(), [task.id]);
+    let tagged = db.tasks_with_tag(&["ops".to_string(), "backend".to_string()], TagMatch::All).await.unwrap();
+    assert!(tagged.is_empty());
+}
+
+#[tokio::test]
+async fn open_subtasks_and_blockers_keep_a_task_open() {
+    let db = database().await;
+    let parent = db.new_task("parent", Priority::Regular, None, None).await.unwrap();
+    let subtask = db.add_subtask(parent.id, "subtask", Priority::Regular).await.unwrap();
+    let blocker = db.new_task("blocker", Priority::Regular, None, None).await.unwrap();
+    db.add_dependency(parent.id, blocker.id).await.unwrap();
+
+    let error = db.mark_task_done(parent.id).await.unwrap_err();
+    assert!(matches!(error, Error::Conflict(ref message) if message.contains("open subtasks")), "{:?}", error);
+    db.mark_task_done(subtask.id).await.unwrap();
+    let error = db.mark_task_done(parent.id).await.unwrap_err();
+    assert!(matches!(error, Error::Conflict(ref message) if message.contains("blocked")), "{:?}", error);
+    assert!(matches!(db.mark_task_done(parent.id + 100).await, Err(Error::NotFound)));
+
+    db.mark_task_done(blocker.id).await.unwrap();
+    let done = db.mark_task_done(parent.id).await.unwrap();
+    assert!(done.completed);
+    // Marking it done again keeps the first completion time
+    assert_eq!(db.mark_task_done(parent.id).await.unwrap().completed_at, done.completed_at);
+}
+
+#[tokio::test]
+async fn finished_parents_are_completed_up_the_tree() {
+    let db = database().await;
+    let root = db.new_task("root", Priority::Regular, None, None).await.unwrap();
+    let middle = db.add_subtask(root.id, "middle", Priority::Regular).await.unwrap();
+    let leaf = db.add_subtask(middle.id, "leaf", Priority::Regular).await.unwrap();
+    let sibling = db.add_subtask(root.id, "sibling", Priority::Regular).await.unwrap();
+
+    db.mark_task_done(leaf.id).await.unwrap();
+    db.complete_finished_parents(leaf.id).await.unwrap();
+    assert!(db.query_task_by_id(middle.id).await.unwrap().completed);
+    assert!(!db.query_task_by_id(root.id).await.unwrap().completed);
+
+    db.mark_task_done(sibling.id).await.unwrap();
+    db.complete_finished_parents(sibling.id).await.unwrap();
+    assert!(db.query_task_by_id(root.id).await.unwrap().completed);
+}
+
+#[tokio::test]
+async fn cycles_are_refused() {
+    let db = database().await;
+    let a = db.new_task("a", Priority::Regular, None, None).await.unwrap();
+    let b = db.add_subtask(a.id, "b", Priority::Regular).await.unwrap();
+    let c = db.add_subtask(b.id, "c", Priority::Regular).await.unwrap();
+
+    assert!(matches!(db.move_subtask(a.id, Some(c.id)).await, Err(Error::Validation(_))));
+    assert!(matches!(db.move_subtask(a.id, Some(a.id)).await, Err(Error::Validation(_))));
+    assert!(matches!(db.move_subtask(c.id, Some(c.id + 100)).await, Err(Error::NotFound)));
+    assert_eq!(db.move_subtask(c.id, None).await.unwrap().parent_id, None);
+
+    db.add_dependency(b.id, a.id).await.unwrap();
+    db.add_dependency(c.id, b.id).await.unwrap();
+    assert!(matches!(db.add_dependency(a.id, c.id).await, Err(Error::Validation(_))));
+    assert!(matches!(db.add_dependency(a.id, a.id).await, Err(Error::Validation(_))));
+    assert!(matches!(db.add_dependency(a.id, c.id + 100).await, Err(Error::NotFound)));
+    assert_eq!(db.query_task_by_id(a.id).await.unwrap().blocked_by, Vec::<i32>::new());
+}
+
+#[tokio::test]
+async fn deleting_a_task_takes_its_subtasks_and_dependencies_along() {
+    let db = database().await;
+    let parent = db.new_task("parent", Priority::Regular, None, None).await.unwrap();
+    let subtask = db.add_subtask(parent.id, "subtask", Priority::Regular).await.unwrap();
+    db.add_tag(subtask.id, "ops").await.unwrap();
+    let waiting = db.new_task("waiting", Priority::Regular, None, None).await.unwrap();
+    db.add_dependency(waiting.id, subtask.id).await.unwrap();
+
+    db.delete_task(parent.id).await.unwrap();
+    assert!(matches!(db.query_task_by_id(subtask.id).await, Err(Error::NotFound)));
+    assert!(db.query_task_by_id(waiting.id).await.unwrap().blocked_by.is_empty());
+    assert!(db.tasks_with_tag(&["ops".to_string()], TagMatch::Any).await.unwrap().is_empty());
+}
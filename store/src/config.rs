@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use clap::{Arg, ArgAction, Command};
+use clap::error::ErrorKind;
+use serde::Deserialize;
+use toml::Spanned;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use crate::ServerConfig;
+
+// Looked for in the working directory when neither `--config` nor `TODO_CONFIG` names a file.
+const DEFAULT_CONFIG_FILE: &str = "todo.toml";
+
+// Every setting, with the environment variable and command line flag that override it. The
+// database URL's variable differs per server binary and is filled in by `Config::load`.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    flag: &'static str,
+}
+
+const DATABASE_URL: &str = "database.url";
+
+const SETTINGS: &[Setting] = &[
+    Setting { key: "backend", env: "TODO_BACKEND", flag: "--backend" },
+    Setting { key: "bind_addr", env: "TODO_BIND_ADDR", flag: "--bind" },
+    Setting { key: "http_addr", env: "TODO_HTTP_ADDR", flag: "--http-addr" },
+    Setting { key: "log_level", env: "TODO_LOG_LEVEL", flag: "--log-level" },
+    Setting { key: "log_format", env: "TODO_LOG_FORMAT", flag: "--log-format" },
+    Setting { key: DATABASE_URL, env: "", flag: "--database-url" },
+    Setting { key: "database.pool_size", env: "TODO_POOL_SIZE", flag: "--pool-size" },
+    Setting { key: "database.timeout_secs", env: "TODO_DB_TIMEOUT_SECS", flag: "--db-timeout" },
+    Setting { key: "limits.max_frame_size", env: "TODO_MAX_FRAME_SIZE", flag: "--max-frame-size" },
+    Setting { key: "limits.idle_timeout_secs", env: "TODO_IDLE_TIMEOUT_SECS", flag: "--idle-timeout" },
+    Setting { key: "limits.idempotency_window_secs", env: "TODO_IDEMPOTENCY_WINDOW_SECS", flag: "--idempotency-window" },
+    Setting { key: "limits.shutdown_timeout_secs", env: "TODO_SHUTDOWN_TIMEOUT_SECS", flag: "--shutdown-timeout" },
+    Setting { key: "tasks.auto_complete_parents", env: "TODO_AUTO_COMPLETE_PARENTS", flag: "--auto-complete-parents" },
+];
+
+// Where a value came from, so an error can point at the right place to fix it.
+#[derive(Clone, Debug)]
+enum Source {
+    File(PathBuf, usize),
+    Env(String),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path, line) => write!(f, "{} line {}", path.display(), line),
+            Source::Env(name) => write!(f, "${}", name),
+            Source::Flag(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+// Every problem found while loading the configuration, reported together so they can all be
+// fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError {
+    problems: Vec<String>,
+}
+
+impl ConfigError {
+
+    pub fn new(problem: &str) -> Self {
+        Self { problems: vec![problem.to_string()] }
+    }
+
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // Human readable, each event over a few lines.
+    Pretty,
+    // One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+// Installs the process wide logger, later calls are ignored. Every span also logs how long it was
+// open when it closes, which is where command and query latencies show up.
+pub fn init_logging(level: LogLevel, format: LogFormat) {
+    let level = match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    };
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::default().add_directive(level.into()))
+        .with_span_events(FmtSpan::CLOSE)
+        // Colours only for a terminal, not for files or log collectors
+        .with_ansi(std::io::stdout().is_terminal());
+    let _ = match format {
+        LogFormat::Pretty => logger.pretty().try_init(),
+        LogFormat::Json => logger.json().try_init(),
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    // How long connecting or waiting for a free pooled connection may take.
+    pub timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub backend: Option<String>,
+    pub bind_addr: String,
+    // Where the monitoring endpoints are served over HTTP, off when unset.
+    pub http_addr: Option<String>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub database_url: Option<String>,
+    pub pool: PoolConfig,
+    pub server: ServerConfig,
+    // Positional command line arguments, left for the binary to interpret.
+    pub args: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            bind_addr: "0.0.0.0:8992".to_string(),
+            http_addr: None,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Pretty,
+            database_url: None,
+            pool: PoolConfig::default(),
+            server: ServerConfig::default(),
+            args: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+
+    // Layers, later ones winning: defaults, the TOML file, `.env`, the environment, command line
+    // flags. `database_url_env` is the variable holding the database URL for this binary.
+    pub fn load(database_url_env: &str) -> Result<Self, ConfigError> {
+        // A missing .env is fine, the real environment is used as is then
+        let _ = dotenvy::dotenv();
+        Self::from_sources(std::env::args().skip(1), |name| std::env::var(name).ok(), database_url_env)
+    }
+
+    pub fn from_sources<A, E>(args: A, env: E, database_url_env: &str) -> Result<Self, ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut problems = Vec::new();
+        let mut values: HashMap<&'static str, (String, Source)> = HashMap::new();
+
+        let (flags, args, config_file) = parse_args(args, &mut problems);
+
+        let (path, required) = match config_file.or_else(|| env("TODO_CONFIG")) {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => match toml::from_str::<FileConfig>(&text) {
+                Ok(file) => {
+                    for (key, value, offset) in file.entries() {
+                        values.insert(key, (value, Source::File(path.clone(), line_at(&text, offset))));
+                    }
+                },
+                Err(e) => match e.span() {
+                    Some(span) => problems.push(format!("{} line {}: {}", path.display(), line_at(&text, span.start), e.message())),
+                    None => problems.push(format!("{}: {}", path.display(), e.message())),
+                },
+            },
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                problems.push(format!("Couldn't read config file {}: {}", path.display(), e));
+            },
+            Err(_) => {},
+        }
+
+        for setting in SETTINGS {
+            let name = if setting.key == DATABASE_URL { database_url_env } else { setting.env };
+            if let Some(value) = env(name) {
+                values.insert(setting.key, (value, Source::Env(name.to_string())));
+            }
+        }
+        for (setting, value) in flags {
+            values.insert(setting.key, (value, Source::Flag(setting.flag)));
+        }
+
+        let mut config = Config { args, ..Config::default() };
+        let mut settings = Values { values, problems: &mut problems };
+        if let Some(backend) = settings.string("backend") {
+            config.backend = Some(backend);
+        }
+        if let Some(addr) = settings.parse("bind_addr", "a host:port address", valid_bind_addr) {
+            config.bind_addr = addr;
+        }
+        config.http_addr = settings.parse("http_addr", "a host:port address", valid_bind_addr);
+        if let Some(level) = settings.parse("log_level", "one of error, warn, info, debug, trace", |s| s.parse().ok()) {
+            config.log_level = level;
+        }
+        if let Some(format) = settings.parse("log_format", "pretty or json", |s| s.parse().ok()) {
+            config.log_format = format;
+        }
+        config.database_url = settings.string(DATABASE_URL);
+        if let Some(size) = settings.parse("database.pool_size", "a positive number of connections", positive) {
+            config.pool.max_connections = size;
+        }
+        if let Some(secs) = settings.parse("database.timeout_secs", "a positive number of seconds", positive) {
+            config.pool.timeout = Duration::from_secs(secs);
+        }
+        if let Some(size) = settings.parse("limits.max_frame_size", "a byte count between 1 and 4294967295", frame_size) {
+            config.server.max_frame_size = size;
+        }
+        // 0 turns the idle timeout off
+        if let Some(secs) = settings.parse("limits.idle_timeout_secs", "a number of seconds", |s| s.parse::<u64>().ok()) {
+            config.server.idle_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = settings.parse("limits.idempotency_window_secs", "a positive number of seconds", positive::<i64>) {
+            config.server.idempotency_window = chrono::Duration::seconds(secs);
+        }
+        if let Some(secs) = settings.parse("limits.shutdown_timeout_secs", "a number of seconds", |s| s.parse::<u64>().ok()) {
+            config.server.shutdown_timeout = Duration::from_secs(secs);
+        }
+        if let Some(enabled) = settings.parse("tasks.auto_complete_parents", "true or false", |s| s.parse().ok()) {
+            config.server.auto_complete_parents = enabled;
+        }
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+}
+
+struct Values<'a> {
+    values: HashMap<&'static str, (String, Source)>,
+    problems: &'a mut Vec<String>,
+}
+
+impl Values<'_> {
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key).map(|(value, _)| value)
+    }
+
+    fn parse<T>(&mut self, key: &str, expected: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+        let (value, source) = self.values.remove(key)?;
+        let parsed = parse(value.trim());
+        if parsed.is_none() {
+            self.problems.push(format!("{} (from {}) must be {}, got '{}'", key, source, expected, value));
+        }
+        parsed
+    }
+
+}
+
+fn positive<T: FromStr + PartialOrd + Default>(s: &str) -> Option<T> {
+    s.parse().ok().filter(|n| *n > T::default())
+}
+
+fn frame_size(s: &str) -> Option<usize> {
+    positive::<u32>(s).map(|n| n as usize)
+}
+
+// Host names are resolved when binding, only the shape is checked here.
+fn valid_bind_addr(s: &str) -> Option<String> {
+    let (host, port) = s.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    (!host.is_empty()).then(|| s.to_string())
+}
+
+// The file's layout. Values keep their span so a bad one can be reported with its line number;
+// they're turned back into strings and checked like the environment and flags.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    backend: Option<Spanned<String>>,
+    bind_addr: Option<Spanned<String>>,
+    http_addr: Option<Spanned<String>>,
+    log_level: Option<Spanned<String>>,
+    log_format: Option<Spanned<String>>,
+    database: DatabaseSection,
+    limits: LimitsSection,
+    tasks: TasksSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    url: Option<Spanned<String>>,
+    pool_size: Option<Spanned<i64>>,
+    timeout_secs: Option<Spanned<i64>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_frame_size: Option<Spanned<i64>>,
+    idle_timeout_secs: Option<Spanned<i64>>,
+    idempotency_window_secs: Option<Spanned<i64>>,
+    shutdown_timeout_secs: Option<Spanned<i64>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TasksSection {
+    auto_complete_parents: Option<Spanned<bool>>,
+}
+
+impl FileConfig {
+
+    // The values that are set, keyed like `SETTINGS`, with the byte offset they start at.
+    fn entries(self) -> Vec<(&'static str, String, usize)> {
+        fn entry<T: ToString>(key: &'static str, value: Option<Spanned<T>>) -> Option<(&'static str, String, usize)> {
+            value.map(|value| (key, value.get_ref().to_string(), value.span().start))
+        }
+        [
+            entry("backend", self.backend),
+            entry("bind_addr", self.bind_addr),
+            entry("http_addr", self.http_addr),
+            entry("log_level", self.log_level),
+            entry("log_format", self.log_format),
+            entry(DATABASE_URL, self.database.url),
+            entry("database.pool_size", self.database.pool_size),
+            entry("database.timeout_secs", self.database.timeout_secs),
+            entry("limits.max_frame_size", self.limits.max_frame_size),
+            entry("limits.idle_timeout_secs", self.limits.idle_timeout_secs),
+            entry("limits.idempotency_window_secs", self.limits.idempotency_window_secs),
+            entry("limits.shutdown_timeout_secs", self.limits.shutdown_timeout_secs),
+            entry("tasks.auto_complete_parents", self.tasks.auto_complete_parents),
+        ].into_iter().flatten().collect()
+    }
+
+}
+
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+type Flags = Vec<(&'static Setting, String)>;
+
+// One `--flag value` (or `--flag=value`) per setting plus `--config`, anything else is a
+// positional argument. `--help` prints the flags and exits.
+fn parse_args<A: IntoIterator<Item = String>>(args: A, problems: &mut Vec<String>) -> (Flags, Vec<String>, Option<String>) {
+    // Named after the running binary so the usage line matches what was typed
+    let name = std::env::args_os().next()
+        .and_then(|arg0| Some(PathBuf::from(arg0).file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "todo".to_string());
+    let command = SETTINGS.iter().fold(
+        Command::new("todo")
+            .bin_name(name)
+            .no_binary_name(true)
+            .args_override_self(true)
+            .arg(Arg::new("config").long("config").value_name("FILE").help("TOML file to read settings from"))
+            .arg(Arg::new("args").num_args(0..).action(ArgAction::Append)),
+        |command, setting| command.arg(Arg::new(setting.key).long(&setting.flag[2..]).value_name("VALUE").help(setting.key)),
+    );
+    let mut matches = match command.try_get_matches_from(args) {
+        Ok(matches) => matches,
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => e.exit(),
+        Err(e) => {
+            let message = e.to_string();
+            let first = message.lines().next().unwrap_or_default();
+            problems.push(first.trim_start_matches("error: ").to_string());
+            return (Vec::new(), Vec::new(), None);
+        },
+    };
+    let flags = SETTINGS.iter()
+        .filter_map(|setting| Some((setting, matches.remove_one::<String>(setting.key)?)))
+        .collect();
+    let positional = matches.remove_many::<String>("args").map(Iterator::collect).unwrap_or_default();
+    (flags, positional, matches.remove_one::<String>("config"))
+}
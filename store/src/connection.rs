@@ -0,0 +1,624 @@
+use serde::{Deserialize, Serialize};
+use thiserror::{Error as ThisError};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use chrono::{Duration, Utc};
+use tokio_util::codec::Framed;
+use futures_util::{SinkExt, StreamExt};
+use tracing::{error, info, info_span, warn, field, Instrument};
+use frame_codec::{CodecError, FrameCodec, DEFAULT_MAX_FRAME_SIZE};
+use net::{ClientRequest, Command, CommandError, CommandResponse, CommandResponseValue, CommandResult, ErrorKind, Hello, HelloResponse, ServerResponse};
+use crate::{TaskStore, Error};
+use crate::health::health_report;
+use crate::metrics::{MeteredStream, Metrics};
+
+#[derive(ThisError, Debug)]
+pub enum ConnectionError {
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Framing error: {0}")]
+    Codec(#[from] CodecError),
+
+}
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_TAG_LEN: usize = 50;
+const MAX_PROJECT_NAME_LEN: usize = 100;
+
+// Checked here rather than by each backend, so every store rejects the same titles.
+fn validate_title(title: &str) -> Result<(), Error> {
+    if title.trim().is_empty() {
+        return Err(Error::Validation("Task title can't be empty".to_string()));
+    }
+    if title.chars().count() > MAX_TITLE_LEN {
+        return Err(Error::Validation(format!("Task title can't be longer than {} characters", MAX_TITLE_LEN)));
+    }
+    Ok(())
+}
+
+// Names are stored trimmed, so " Home" and "Home" can't both exist.
+fn normalize_project_name(name: &str) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::Validation("Project name can't be empty".to_string()));
+    }
+    if name.chars().count() > MAX_PROJECT_NAME_LEN {
+        return Err(Error::Validation(format!("Project name can't be longer than {} characters", MAX_PROJECT_NAME_LEN)));
+    }
+    Ok(name.to_string())
+}
+
+// Tags are compared case-insensitively, so they're stored lowercased.
+fn normalize_tag(tag: &str) -> Result<String, Error> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() {
+        return Err(Error::Validation("Tag can't be empty".to_string()));
+    }
+    if tag.chars().any(char::is_whitespace) {
+        return Err(Error::Validation("Tag can't contain spaces".to_string()));
+    }
+    if tag.chars().count() > MAX_TAG_LEN {
+        return Err(Error::Validation(format!("Tag can't be longer than {} characters", MAX_TAG_LEN)));
+    }
+    Ok(tag)
+}
+
+// `atomic` is set when the command runs inside the transaction of an atomic request.
+async fn run_command<S: TaskStore>(db: &S, config: &ServerConfig, atomic: bool, command: Command<S::Id>) -> Result<CommandResponseValue<S::Task, S::Id>, Error> {
+    match command {
+        Command::NewTask { title, priority, due_at, project_id } => {
+            validate_title(&title)?;
+            db.new_task(&title, priority, due_at, project_id).await.map(CommandResponseValue::NewTask)
+        },
+        Command::PendingTasks => {
+            db.pending_tasks().await.map(CommandResponseValue::PendingTasks)
+        },
+        Command::DoneTasks => {
+            db.done_tasks().await.map(CommandResponseValue::DoneTasks)
+        },
+        Command::MarkTaskDone(id) => {
+            let task = db.mark_task_done(id.clone()).await?;
+            if config.auto_complete_parents && let Err(e) = db.complete_finished_parents(id).await {
+                // Inside a transaction the error rolls back the whole request. Otherwise the task is
+                // done already, and the client must not be told the command failed.
+                if atomic {
+                    return Err(e);
+                }
+                error!(error = %e, "failed to complete the finished parents");
+            }
+            Ok(CommandResponseValue::MarkTaskDone(task))
+        },
+        Command::EditTaskTitle { task_id, new_title } => {
+            validate_title(&new_title)?;
+            db.edit_task_title(task_id, &new_title).await.map(CommandResponseValue::EditTaskTitle)
+        },
+        Command::EditTaskPriority { task_id, priority } => {
+            db.edit_task_priority(task_id, priority).await.map(CommandResponseValue::EditTaskPriority)
+        },
+        Command::QueryTaskById(id) => {
+            db.query_task_by_id(id).await.map(CommandResponseValue::QueryTaskById)
+        },
+        Command::DeleteTask(id) => {
+            db.delete_task(id).await.map(CommandResponseValue::DeleteTask)
+        },
+        Command::ArchiveTask(id) => {
+            db.archive_task(id).await.map(CommandResponseValue::ArchiveTask)
+        },
+        Command::UnarchiveTask(id) => {
+            db.unarchive_task(id).await.map(CommandResponseValue::UnarchiveTask)
+        },
+        Command::ArchivedTasks => {
+            db.archived_tasks().await.map(CommandResponseValue::ArchivedTasks)
+        },
+        Command::ReopenTask(id) => {
+            db.reopen_task(id).await.map(CommandResponseValue::ReopenTask)
+        },
+        Command::SetDueDate { task_id, due_at } => {
+            db.set_due_date(task_id, due_at).await.map(CommandResponseValue::SetDueDate)
+        },
+        Command::OverdueTasks => {
+            db.overdue_tasks(Utc::now().naive_utc()).await.map(CommandResponseValue::OverdueTasks)
+        },
+        Command::DueWithin { days } => {
+            let now = Utc::now().naive_utc();
+            let until = now
+                .checked_add_signed(Duration::days(days.into()))
+                .ok_or_else(|| Error::Validation(format!("{} days from now is out of range", days)))?;
+            db.due_tasks(now, until).await.map(CommandResponseValue::DueWithin)
+        },
+        Command::AddTag { task_id, tag } => {
+            let tag = normalize_tag(&tag)?;
+            db.add_tag(task_id, &tag).await.map(CommandResponseValue::AddTag)
+        },
+        Command::RemoveTag { task_id, tag } => {
+            let tag = normalize_tag(&tag)?;
+            db.remove_tag(task_id, &tag).await.map(CommandResponseValue::RemoveTag)
+        },
+        Command::TasksWithTag { tags, matching } => {
+            let mut tags = tags.iter().map(|tag| normalize_tag(tag)).collect::<Result<Vec<_>, _>>()?;
+            tags.sort();
+            tags.dedup();
+            if tags.is_empty() {
+                return Err(Error::Validation("At least one tag is needed".to_string()));
+            }
+            db.tasks_with_tag(&tags, matching).await.map(CommandResponseValue::TasksWithTag)
+        },
+        Command::NewProject { name } => {
+            let name = normalize_project_name(&name)?;
+            db.new_project(&name).await.map(CommandResponseValue::NewProject)
+        },
+        Command::RenameProject { project_id, name } => {
+            let name = normalize_project_name(&name)?;
+            db.rename_project(project_id, &name).await.map(CommandResponseValue::RenameProject)
+        },
+        Command::DeleteProject(id) => {
+            db.delete_project(id).await.map(CommandResponseValue::DeleteProject)
+        },
+        Command::Projects => {
+            db.projects().await.map(CommandResponseValue::Projects)
+        },
+        Command::MoveTask { task_id, project_id } => {
+            db.move_task(task_id, project_id).await.map(CommandResponseValue::MoveTask)
+        },
+        Command::ProjectPendingTasks(id) => {
+            db.project_tasks(id, false).await.map(CommandResponseValue::ProjectPendingTasks)
+        },
+        Command::ProjectDoneTasks(id) => {
+            db.project_tasks(id, true).await.map(CommandResponseValue::ProjectDoneTasks)
+        },
+        Command::AddSubtask { parent_id, title, priority } => {
+            validate_title(&title)?;
+            db.add_subtask(parent_id, &title, priority).await.map(CommandResponseValue::AddSubtask)
+        },
+        Command::MoveSubtask { task_id, parent_id } => {
+            db.move_subtask(task_id, parent_id).await.map(CommandResponseValue::MoveSubtask)
+        },
+        Command::TaskTree(id) => {
+            db.task_tree(id).await.map(CommandResponseValue::TaskTree)
+        },
+        Command::AddDependency { task_id, blocked_by } => {
+            db.add_dependency(task_id, blocked_by).await.map(CommandResponseValue::AddDependency)
+        },
+        Command::RemoveDependency { task_id, blocked_by } => {
+            db.remove_dependency(task_id, blocked_by).await.map(CommandResponseValue::RemoveDependency)
+        },
+        Command::BlockedTasks => {
+            db.tasks_by_readiness(false).await.map(CommandResponseValue::BlockedTasks)
+        },
+        Command::ReadyTasks => {
+            db.tasks_by_readiness(true).await.map(CommandResponseValue::ReadyTasks)
+        },
+        Command::Ping => Ok(CommandResponseValue::Pong),
+        Command::Health => Ok(CommandResponseValue::Health(health_report(db).await)),
+    }
+}
+
+// Runs one command in its own span, which records how it ended.
+async fn execute<S: TaskStore>(db: &S, config: &ServerConfig, metrics: &Metrics, atomic: bool, command: Command<S::Id>) -> Result<CommandResponseValue<S::Task, S::Id>, Error> {
+    let span = info_span!("command", kind = command.name(), task_id = field::Empty, outcome = field::Empty);
+    if let Some(task_id) = command.task_id() {
+        span.record("task_id", field::display(task_id));
+    }
+    let name = command.name();
+    let started = std::time::Instant::now();
+    let result = run_command(db, config, atomic, command).instrument(span.clone()).await;
+    metrics.command(name, started.elapsed(), result.as_ref().err().map(Error::kind));
+    match &result {
+        Ok(_) => span.record("outcome", "ok"),
+        Err(e) => span.record("outcome", field::debug(e.kind())),
+    };
+    result
+}
+
+fn to_result<T, Id>(result: Result<CommandResponseValue<T, Id>, Error>) -> CommandResult<T, Id> {
+    match result {
+        Ok(value) => CommandResult::Success(value),
+        Err(e) => CommandResult::Error(e.into()),
+    }
+}
+
+// Runs every command as its own task, each one succeeding or failing on its own.
+async fn execute_concurrently<S: TaskStore>(db: &S, config: &ServerConfig, metrics: &Metrics, commands: Vec<Command<S::Id>>) -> Vec<CommandResponse<S::Task, S::Id>> {
+    let expected_responses_len = commands.len();
+
+    // Create channel with enough capacity
+    let (tx, mut rx) = mpsc::channel(expected_responses_len.max(1));
+
+    // Spawn tasks for each command, tagging every response with the command's position
+    for (index, command) in commands.into_iter().enumerate() {
+        let db = db.clone();
+        let config = *config;
+        let metrics = metrics.clone();
+        let tx = tx.clone();
+
+        tokio::spawn(async move {
+            let result = to_result(execute(&db, &config, &metrics, false, command).await);
+            let response = CommandResponse::new(index as u32, result);
+
+            // Send response through channel
+            if let Err(e) = tx.send(response).await {
+                error!(error = %e, "failed to send response");
+            }
+        }.in_current_span());
+    }
+
+    // Drop our sender so the receiver knows when to stop
+    drop(tx);
+
+    // Collect all responses
+    let mut responses = Vec::with_capacity(expected_responses_len);
+    while let Some(response) = rx.recv().await {
+        responses.push(response);
+    }
+    // Commands finish in any order, hand them back in request order
+    responses.sort_by_key(|response| response.index);
+    responses
+}
+
+// The same internal error for every command of a request.
+fn all_failed<T, Id>(len: u32, message: &str) -> Vec<CommandResponse<T, Id>> {
+    let error = CommandError::new(ErrorKind::Internal, message);
+    (0..len)
+        .map(|index| CommandResponse::new(index, CommandResult::Error(error.clone())))
+        .collect()
+}
+
+// Runs the commands in order inside one transaction. On the first error everything is rolled back,
+// the failing command reports its error and every other command reports which one failed.
+async fn execute_atomically<S: TaskStore>(db: &S, config: &ServerConfig, metrics: &Metrics, commands: Vec<Command<S::Id>>) -> Vec<CommandResponse<S::Task, S::Id>> {
+    let len = commands.len() as u32;
+    let tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!(error = %e, "failed to start transaction");
+            return all_failed(len, "Couldn't start transaction");
+        },
+    };
+
+    let mut values = Vec::with_capacity(commands.len());
+    for (failed_index, command) in commands.into_iter().enumerate() {
+        let e = match execute(&tx, config, metrics, true, command).await {
+            Ok(value) => {
+                values.push(value);
+                continue;
+            },
+            Err(e) => e,
+        };
+        if let Err(e) = tx.rollback().await {
+            error!(error = %e, "failed to roll back transaction");
+        }
+        let failed_index = failed_index as u32;
+        let error = CommandError::from(e);
+        return (0..len)
+            .map(|index| {
+                let result = if index == failed_index {
+                    CommandResult::Error(error.clone())
+                } else {
+                    CommandResult::Aborted { failed_index }
+                };
+                CommandResponse::new(index, result)
+            })
+            .collect();
+    }
+
+    if let Err(e) = tx.commit().await {
+        error!(error = %e, "failed to commit transaction");
+        return all_failed(len, "Couldn't commit transaction");
+    }
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| CommandResponse::new(index as u32, CommandResult::Success(value)))
+        .collect()
+}
+
+async fn execute_request<S: TaskStore>(db: &S, config: &ServerConfig, metrics: &Metrics, rq: ClientRequest<S::Id>) -> Vec<CommandResponse<S::Task, S::Id>> {
+    let commands = rq.get_commands().to_vec();
+    if rq.is_atomic() {
+        execute_atomically(db, config, metrics, commands).await
+    } else {
+        execute_concurrently(db, config, metrics, commands).await
+    }
+}
+
+// Failures that may well go away, a retry with the same key should run the request again.
+fn is_transient<T, Id>(response: &CommandResponse<T, Id>) -> bool {
+    matches!(&response.result, CommandResult::Error(e) if matches!(e.kind, ErrorKind::Internal | ErrorKind::Unavailable))
+}
+
+// What is kept for an idempotency key: the encoded request it came with, so the key can't be used to
+// read another request's response, and the responses to replay.
+#[derive(Serialize, Deserialize)]
+struct SavedResponse<T, Id> {
+    request: Vec<u8>,
+    responses: Vec<CommandResponse<T, Id>>,
+}
+
+// A keyed request seen within the idempotency window is answered with the response saved the first
+// time instead of running again. Two copies arriving at the same moment can still both run, the
+// window only covers retries of a request whose response got lost. The same key with a different
+// request rejects the request as a whole.
+async fn execute_idempotent<S: TaskStore>(
+    db: &S,
+    config: &ServerConfig,
+    metrics: &Metrics,
+    key: &str,
+    rq: ClientRequest<S::Id>,
+) -> Result<Vec<CommandResponse<S::Task, S::Id>>, CommandError> {
+    let len = rq.get_commands().len() as u32;
+    let since = Utc::now().naive_utc() - config.idempotency_window;
+    let request = match bincode::serialize(&rq) {
+        Ok(request) => request,
+        Err(e) => {
+            error!(key, error = %e, "failed to encode keyed request");
+            return Ok(all_failed(len, "Couldn't check the idempotency key"));
+        },
+    };
+    match db.saved_response(key, since).await {
+        Ok(Some(saved)) => match bincode::deserialize::<SavedResponse<S::Task, S::Id>>(&saved) {
+            Ok(saved) if saved.request == request => return Ok(saved.responses),
+            Ok(_) => {
+                warn!(key, "idempotency key reused for a different request");
+                return Err(CommandError::new(ErrorKind::Conflict, "The idempotency key was already used for a different request"));
+            },
+            Err(e) => warn!(key, error = %e, "discarding unreadable saved response"),
+        },
+        Ok(None) => {},
+        // Running it without knowing whether it already ran is exactly what the key should prevent
+        Err(e) => {
+            error!(key, error = %e, "failed to look up idempotency key");
+            return Ok(all_failed(len, "Couldn't check the idempotency key"));
+        },
+    }
+
+    let responses = execute_request(db, config, metrics, rq).await;
+    if !responses.iter().any(is_transient) {
+        let saved = SavedResponse { request, responses };
+        let encoded = bincode::serialize(&saved).map_err(Error::database);
+        let stored = match encoded {
+            Ok(encoded) => db.save_response(key, &encoded, since).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            error!(key, error = %e, "failed to save response for idempotency key");
+        }
+        return Ok(saved.responses);
+    }
+    Ok(responses)
+}
+
+// Settings shared by every connection a server accepts.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerConfig {
+    // Largest request frame accepted, in bytes. Bigger frames get an error response and the
+    // connection is closed, since the rest of the stream can't be trusted anymore.
+    pub max_frame_size: usize,
+    // How long the response to a request with an idempotency key is kept for retries.
+    pub idempotency_window: Duration,
+    // Connections without a request for this long are closed, `None` keeps them open.
+    pub idle_timeout: Option<std::time::Duration>,
+    // How long open connections get to finish their current request once the server stops.
+    pub shutdown_timeout: std::time::Duration,
+    // Completing the last open subtask of a task completes the task too.
+    pub auto_complete_parents: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            idempotency_window: Duration::hours(24),
+            idle_timeout: None,
+            shutdown_timeout: std::time::Duration::from_secs(30),
+            auto_complete_parents: false,
+        }
+    }
+}
+
+// The client's first frame must be a compatible `Hello`, otherwise it's told why and dropped.
+async fn handshake(framed: &mut Framed<MeteredStream<TcpStream>, FrameCodec<Hello, HelloResponse>>) -> Result<Option<Hello>, ConnectionError> {
+    let check = match framed.next().await {
+        Some(Ok(Ok(hello))) => hello.check().map(|()| hello),
+        Some(Ok(Err(_)) | Err(CodecError::FrameTooLarge { .. })) => {
+            Err("Expected a handshake, the client is probably too old".to_string())
+        },
+        Some(Err(e)) => {
+            info!(error = %e, "connection lost during handshake");
+            return Ok(None);
+        },
+        None => return Ok(None),
+    };
+    match check {
+        Ok(client) => {
+            framed.send(HelloResponse::Accepted(Hello::new())).await?;
+            Ok(Some(client))
+        },
+        Err(reason) => {
+            info!(reason, "rejecting client");
+            framed.send(HelloResponse::Rejected { server: Hello::new(), reason }).await?;
+            Ok(None)
+        },
+    }
+}
+
+// Serves one client until it disconnects, for callers that run their own accept loop.
+pub async fn handle_connection<S: TaskStore>(
+    stream: TcpStream,
+    addr: std::net::SocketAddr,
+    db: S,
+    config: ServerConfig,
+    metrics: Metrics,
+) -> Result<(), ConnectionError> {
+    let (_never, shutdown) = watch::channel(false);
+    serve_connection(stream, addr, db, config, metrics, shutdown).await
+}
+
+// Requests are never interrupted: `shutdown` is only looked at between them.
+#[tracing::instrument(name = "connection", skip_all, fields(peer = %addr))]
+async fn serve_connection<S: TaskStore>(
+    stream: TcpStream,
+    addr: std::net::SocketAddr,
+    db: S,
+    config: ServerConfig,
+    metrics: Metrics,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), ConnectionError> {
+    info!("client connected");
+    let _connection = metrics.connection();
+    let stream = MeteredStream::new(stream, &metrics);
+    let mut framed = Framed::new(stream, FrameCodec::with_max_frame_size(config.max_frame_size));
+    let Some(client) = handshake(&mut framed).await? else {
+        return Ok(());
+    };
+
+    let mut framed = framed.map_codec(|codec| {
+        FrameCodec::<ClientRequest<S::Id>, ServerResponse<S::Task, S::Id>>::with_max_frame_size(codec.max_frame_size())
+    });
+    loop {
+        let next = async {
+            match config.idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, framed.next()).await.ok(),
+                None => Some(framed.next().await),
+            }
+        };
+        let next = tokio::select! {
+            // Checked first, so a client sending request after request can't hold the shutdown off
+            biased;
+            _ = shutdown.wait_for(|stopping| *stopping) => None,
+            next = next => Some(next),
+        };
+        let frame = match next {
+            Some(Some(Some(frame))) => frame,
+            Some(Some(None)) => break,
+            Some(None) => {
+                info!(idle_timeout = ?config.idle_timeout, "client was idle, closing connection");
+                break;
+            },
+            None => {
+                if client.supports("shutdown-notice") {
+                    let notice = CommandError::new(ErrorKind::ShuttingDown, "Server is shutting down");
+                    framed.send(ServerResponse::rejected(notice)).await?;
+                }
+                break;
+            },
+        };
+        let server_response = match frame {
+            Ok(Ok(rq)) => {
+                metrics.request(rq.get_commands().len());
+                let span = info_span!(
+                    "request",
+                    commands = rq.get_commands().len(),
+                    atomic = rq.is_atomic(),
+                    idempotency_key = rq.idempotency_key(),
+                );
+                let responses = match rq.idempotency_key().map(str::to_string) {
+                    Some(key) if !rq.is_read_only() => {
+                        execute_idempotent(&db, &config, &metrics, &key, rq).instrument(span).await
+                    },
+                    _ => Ok(execute_request(&db, &config, &metrics, rq).instrument(span).await),
+                };
+                match responses {
+                    Ok(responses) => ServerResponse::new(&responses[..]),
+                    Err(e) => ServerResponse::rejected(e),
+                }
+            },
+            // The frame itself was read whole, so the connection survives a bad one
+            Ok(Err(e)) => {
+                warn!(error = %e, "couldn't decode request");
+                let error = CommandError::new(ErrorKind::MalformedRequest, "Couldn't decode request")
+                    .with_details(&e.to_string());
+                ServerResponse::rejected(error)
+            },
+            // Nothing after an oversized length prefix can be trusted, answer and hang up
+            Err(CodecError::FrameTooLarge { len, max }) => {
+                warn!(len, max, "frame exceeds the size limit, dropping connection");
+                let error = CommandError::new(ErrorKind::MalformedRequest, "Request is too large")
+                    .with_details(&format!("{} bytes, the limit is {}", len, max));
+                framed.send(ServerResponse::rejected(error)).await?;
+                break;
+            },
+            Err(e) => {
+                warn!(error = %e, "unreliable connection, dropping it");
+                break;
+            },
+        };
+        framed.send(server_response).await?;
+    }
+
+    Ok(())
+}
+
+// Resolves on Ctrl-C, or on SIGTERM where there is one, which is how containers are stopped.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!(error = %e, "couldn't listen for SIGTERM, only Ctrl-C stops the server gracefully");
+                return tokio::signal::ctrl_c().await;
+            },
+        };
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+// Accept loop shared by every server binary, runs until Ctrl-C or SIGTERM. Open connections then
+// finish the request they're running and are told the server is going away, those still busy
+// after `shutdown_timeout` are cut off.
+pub async fn serve<S: TaskStore>(listener: TcpListener, db: S, config: ServerConfig, metrics: Metrics) -> Result<(), ConnectionError> {
+    info!(addr = %listener.local_addr()?, "listening");
+
+    let (stop, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut watching_signals = true;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    let db = db.clone();
+                    let metrics = metrics.clone();
+                    let stopping = stopping.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = serve_connection(stream, addr, db, config, metrics, stopping).await {
+                            error!(error = %e, "connection handler failed");
+                        }
+                    });
+                },
+                Err(e) => error!(error = %e, "failed to accept connection"),
+            },
+            // Reap finished connections so the set doesn't grow for the server's whole life
+            Some(_) = connections.join_next(), if !connections.is_empty() => {},
+            result = &mut signal, if watching_signals => match result {
+                Ok(()) => break,
+                // Not being able to stop gracefully is no reason to stop serving now
+                Err(e) => {
+                    error!(error = %e, "couldn't listen for Ctrl-C, only killing the process stops the server");
+                    watching_signals = false;
+                },
+            },
+        }
+    }
+
+    drop(listener);
+    info!(open_connections = connections.len(), "shutting down server");
+    let _ = stop.send(true);
+    let drained = tokio::time::timeout(config.shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        warn!(busy_connections = connections.len(), timeout = ?config.shutdown_timeout, "connections still busy, dropping them");
+        connections.shutdown().await;
+    }
+    Ok(())
+}
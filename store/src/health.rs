@@ -0,0 +1,25 @@
+use std::time::Duration;
+use net::{HealthCheck, HealthReport};
+use crate::TaskStore;
+
+// Backend checks taking longer than this count as the database being unreachable, so a probe
+// never hangs on a pool that can't hand out a connection.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the `Health` command and `/health/ready` report.
+pub async fn health_report<S: TaskStore>(db: &S) -> HealthReport {
+    let mut checks = match tokio::time::timeout(CHECK_TIMEOUT, db.health_checks()).await {
+        Ok(checks) => checks,
+        Err(_) => vec![
+            HealthCheck::new("database", false)
+                .with_details(&format!("no answer within {}s", CHECK_TIMEOUT.as_secs())),
+        ],
+    };
+    if let Some(pool) = db.pool_status() {
+        // Exhausted when every connection the pool may open is checked out
+        let exhausted = pool.open >= pool.max && pool.idle == 0;
+        let details = format!("{} of {} connections in use", pool.open.saturating_sub(pool.idle), pool.max);
+        checks.push(HealthCheck::new("pool", !exhausted).with_details(&details));
+    }
+    HealthReport { checks }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+use axum::Router;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+use crate::TaskStore;
+use crate::health::health_report;
+use crate::metrics::Metrics;
+
+// Request heads bigger than this are refused, scrapers and probes send a few hundred bytes. It's
+// also the smallest read buffer hyper accepts.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct HttpState<S> {
+    db: S,
+    metrics: Metrics,
+}
+
+async fn render_metrics<S: TaskStore>(State(state): State<HttpState<S>>) -> impl IntoResponse {
+    let body = state.metrics.render(state.db.pool_status());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], body)
+}
+
+// The process is up and answering, whatever state the database is in
+async fn live() -> &'static str {
+    "ok\n"
+}
+
+// Whether requests can be served right now, with every check listed either way
+async fn ready<S: TaskStore>(State(state): State<HttpState<S>>) -> (StatusCode, String) {
+    let report = health_report(&state.db).await;
+    let status = if report.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = if report.checks.is_empty() { "ok\n".to_string() } else { report.to_string() };
+    (status, body)
+}
+
+fn router<S: TaskStore>(db: S, metrics: Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(render_metrics::<S>))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready::<S>))
+        .with_state(HttpState { db, metrics })
+}
+
+// HTTP/1.1 side listener for monitoring, one request per connection. Runs until the task running
+// it is aborted.
+pub async fn serve_http<S: TaskStore>(listener: TcpListener, db: S, metrics: Metrics) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "serving metrics and health checks over http");
+    }
+    let service = TowerToHyperService::new(router(db, metrics));
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let connection = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(READ_TIMEOUT)
+                    .max_buf_size(MAX_HEAD_SIZE)
+                    .keep_alive(false)
+                    .serve_connection(TokioIo::new(stream), service.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        debug!(error = %e, "http connection failed");
+                    }
+                });
+            },
+            Err(e) => warn!(error = %e, "failed to accept http connection"),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::future::Future;
+use chrono::NaiveDateTime;
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::{Error as ThisError};
+use net::{Priority, CommandError, ErrorKind, HealthCheck, Project, TagMatch, TaskTree};
+
+mod config;
+mod connection;
+mod health;
+mod http;
+mod metrics;
+mod memory;
+
+pub use config::{init_logging, Config, ConfigError, LogFormat, LogLevel, PoolConfig};
+pub use connection::{handle_connection, serve, ConnectionError, ServerConfig};
+pub use health::health_report;
+pub use http::serve_http;
+pub use memory::MemoryTaskStore;
+pub use metrics::Metrics;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Connections of a backend's pool at one moment.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStatus {
+    pub open: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+// Common error every storage backend maps its driver errors into.
+#[derive(ThisError, Debug)]
+pub enum Error {
+
+    #[error("Task not found")]
+    NotFound,
+
+    #[error("Project not found")]
+    ProjectNotFound,
+
+    #[error("Invalid task id: {0}")]
+    InvalidId(String),
+
+    #[error("Validation failed: {0}")]
+    Validation(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Permission denied: {0}")]
+    Unauthorized(String),
+
+    #[error("Database unavailable: {0}")]
+    Unavailable(#[source] BoxError),
+
+    #[error("Database error: {0}")]
+    Database(#[source] BoxError),
+
+    #[error("Transaction error: {0}")]
+    Transaction(String),
+
+}
+
+impl Error {
+
+    pub fn database<E>(e: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::Database(Box::new(e))
+    }
+
+    pub fn unavailable<E>(e: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::Unavailable(Box::new(e))
+    }
+
+    // How the error is reported to the client.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NotFound | Error::ProjectNotFound => ErrorKind::NotFound,
+            Error::InvalidId(_) => ErrorKind::InvalidId,
+            Error::Validation(_) => ErrorKind::ValidationFailed,
+            Error::Conflict(_) => ErrorKind::Conflict,
+            Error::Unauthorized(_) => ErrorKind::Unauthorized,
+            Error::Unavailable(_) => ErrorKind::Unavailable,
+            Error::Database(_) | Error::Transaction(_) => ErrorKind::Internal,
+        }
+    }
+
+}
+
+// What the client gets to see. Database errors are logged here and replaced by a generic message,
+// driver messages are no business of the client.
+impl From<Error> for CommandError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::NotFound | Error::ProjectNotFound => CommandError::new(ErrorKind::NotFound, &e.to_string()),
+            Error::InvalidId(details) => {
+                CommandError::new(ErrorKind::InvalidId, "Invalid task id").with_details(&details)
+            },
+            Error::Validation(message) => CommandError::new(ErrorKind::ValidationFailed, &message),
+            Error::Conflict(message) => CommandError::new(ErrorKind::Conflict, &message),
+            Error::Unauthorized(_) => {
+                tracing::warn!(error = %e, "command failed");
+                CommandError::new(ErrorKind::Unauthorized, "Permission denied")
+            },
+            Error::Unavailable(_) => {
+                tracing::warn!(error = %e, "command failed");
+                CommandError::new(ErrorKind::Unavailable, "Database unavailable, try again later")
+            },
+            Error::Database(_) | Error::Transaction(_) => {
+                tracing::error!(error = %e, "command failed");
+                CommandError::new(ErrorKind::Internal, "Internal server error")
+            },
+        }
+    }
+}
+
+// Operations the server needs from a storage backend. `Id` is the id type carried by the
+// `net::Command`s the backend accepts and `Task` is what it hands back in responses.
+pub trait TaskStore: Clone + Send + Sync + 'static {
+
+    type Id: Serialize + DeserializeOwned + Clone + Send + std::fmt::Display + 'static;
+    type Task: Serialize + DeserializeOwned + Clone + Send + 'static;
+
+    // Starts a transaction. Every operation on the returned store runs inside it until it is
+    // committed or rolled back; the store it was started from is unaffected.
+    fn begin(&self) -> impl Future<Output = Result<Self, Error>> + Send;
+
+    fn commit(self) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn rollback(self) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // A task can be created right inside a project, `ProjectNotFound` if there is no such project.
+    fn new_task(&self, title: &str, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<Self::Id>) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn pending_tasks(&self) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    fn done_tasks(&self) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // Records the completion time, marking an already completed task again keeps the original one.
+    // A task with open, unarchived subtasks is refused with a `Conflict`, and so is one still blocked
+    // by an open, unarchived task.
+    fn mark_task_done(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Marks a completed task as pending again and clears its completion time.
+    fn reopen_task(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn edit_task_title(&self, task_id: Self::Id, title: &str) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn edit_task_priority(&self, task_id: Self::Id, priority: Priority) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn query_task_by_id(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Removes the task for good and returns it as it was before deletion.
+    fn delete_task(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Archived tasks are left out of `pending_tasks` and `done_tasks`.
+    fn archive_task(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn unarchive_task(&self, task_id: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn archived_tasks(&self) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // `None` clears the due date.
+    fn set_due_date(&self, task_id: Self::Id, due_at: Option<NaiveDateTime>) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Open, unarchived tasks due before `now`, soonest due first.
+    fn overdue_tasks(&self, now: NaiveDateTime) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // Open, unarchived tasks due from `from` to `until`, both included, soonest due first.
+    fn due_tasks(&self, from: NaiveDateTime, until: NaiveDateTime) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // Tags reach the backend already normalized, see `Command::AddTag`.
+    fn add_tag(&self, task_id: Self::Id, tag: &str) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn remove_tag(&self, task_id: Self::Id, tag: &str) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Unarchived tasks carrying all or any of `tags`, which is never empty nor has duplicates.
+    fn tasks_with_tag(&self, tags: &[String], matching: TagMatch) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // Project names are unique, a taken name is a `Conflict`.
+    fn new_project(&self, name: &str) -> impl Future<Output = Result<Project<Self::Id>, Error>> + Send;
+
+    fn rename_project(&self, project_id: Self::Id, name: &str) -> impl Future<Output = Result<Project<Self::Id>, Error>> + Send;
+
+    // The project's tasks are kept and no longer belong to any project.
+    fn delete_project(&self, project_id: Self::Id) -> impl Future<Output = Result<Project<Self::Id>, Error>> + Send;
+
+    // Every project, oldest first.
+    fn projects(&self) -> impl Future<Output = Result<Vec<Project<Self::Id>>, Error>> + Send;
+
+    // `None` takes the task out of its project.
+    fn move_task(&self, task_id: Self::Id, project_id: Option<Self::Id>) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Unarchived tasks of the project that are done or not, an unknown project has none.
+    fn project_tasks(&self, project_id: Self::Id, completed: bool) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // The subtask starts out in its parent's project.
+    fn add_subtask(&self, parent_id: Self::Id, title: &str, priority: Priority) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // `None` makes it a top-level task. Moving a task below itself or one of its own subtasks is a
+    // `Validation` error.
+    fn move_subtask(&self, task_id: Self::Id, parent_id: Option<Self::Id>) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn task_tree(&self, task_id: Self::Id) -> impl Future<Output = Result<TaskTree<Self::Task>, Error>> + Send;
+
+    // Marks the task's parent done once none of its subtasks is open anymore and nothing blocks it,
+    // then the parent's parent and so on up the tree.
+    fn complete_finished_parents(&self, task_id: Self::Id) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // `task_id` waits on `blocked_by` from now on, adding a dependency twice changes nothing. One
+    // that would make a task wait on itself, directly or through other tasks, is a `Validation` error.
+    fn add_dependency(&self, task_id: Self::Id, blocked_by: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    fn remove_dependency(&self, task_id: Self::Id, blocked_by: Self::Id) -> impl Future<Output = Result<Self::Task, Error>> + Send;
+
+    // Open, unarchived tasks that are waiting on an open, unarchived task or, for `ready`, that
+    // aren't waiting on any. Oldest first.
+    fn tasks_by_readiness(&self, ready: bool) -> impl Future<Output = Result<Vec<Self::Task>, Error>> + Send;
+
+    // Serialized response to the request with this idempotency key, if one was saved after `since`.
+    fn saved_response(&self, key: &str, since: NaiveDateTime) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+
+    // Saves the response to a keyed request, replacing an older one with the same key, and forgets
+    // every response saved before `expired_before`.
+    fn save_response(&self, key: &str, response: &[u8], expired_before: NaiveDateTime) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // The backend's own readiness checks, such as whether the database answers and the schema is
+    // up to date. The pool check is common to every backend and done by `health_report`.
+    fn health_checks(&self) -> impl Future<Output = Vec<HealthCheck>> + Send;
+
+    // For the metrics, `None` when the backend has no connection pool.
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+
+}
@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use chrono::{NaiveDateTime, Utc};
+use net::{Task, Priority, HealthCheck, Project, TagMatch, TaskTree};
+use crate::{TaskStore, Error};
+
+#[derive(Clone)]
+struct Tasks {
+    next_id: i32,
+    tasks: BTreeMap<i32, Task>,
+    next_project_id: i32,
+    projects: BTreeMap<i32, Project>,
+}
+
+impl Tasks {
+
+    fn filter<P>(&self, predicate: P) -> Vec<Task>
+    where
+        P: Fn(&Task) -> bool,
+    {
+        self.tasks
+            .values()
+            .filter(|task| predicate(task))
+            .cloned()
+            .collect()
+    }
+
+    fn update<F>(&mut self, task_id: i32, update: F) -> Result<Task, Error>
+    where
+        F: FnOnce(&mut Task),
+    {
+        let task = self.tasks.get_mut(&task_id).ok_or(Error::NotFound)?;
+        update(task);
+        Ok(task.clone())
+    }
+
+    fn check_project(&self, project_id: Option<i32>) -> Result<(), Error> {
+        match project_id {
+            Some(project_id) if !self.projects.contains_key(&project_id) => Err(Error::ProjectNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn has_open_subtasks(&self, task_id: i32) -> bool {
+        self.tasks.values().any(|task| task.parent_id == Some(task_id) && is_open(task))
+    }
+
+    // Ids of the subtasks below `task_id` at any depth, in id order.
+    fn descendants(&self, task_id: i32) -> Vec<i32> {
+        let mut found = Vec::new();
+        let mut pending = vec![task_id];
+        while let Some(id) = pending.pop() {
+            for task in self.tasks.values().filter(|task| task.parent_id == Some(id)) {
+                found.push(task.id);
+                pending.push(task.id);
+            }
+        }
+        found.sort();
+        found
+    }
+
+    fn is_blocked(&self, task_id: i32) -> bool {
+        self.tasks.get(&task_id).is_some_and(|task| {
+            task.blocked_by.iter().any(|id| self.tasks.get(id).is_some_and(is_open))
+        })
+    }
+
+    // Whether `task_id` waits on `blocker_id`, directly or through other tasks.
+    fn waits_on(&self, task_id: i32, blocker_id: i32) -> bool {
+        let mut seen = Vec::new();
+        let mut pending = vec![task_id];
+        while let Some(id) = pending.pop() {
+            if id == blocker_id {
+                return true;
+            }
+            if !seen.contains(&id) {
+                seen.push(id);
+                pending.extend(self.tasks.get(&id).map(|task| task.blocked_by.clone()).unwrap_or_default());
+            }
+        }
+        false
+    }
+
+    // `renamed` is the project getting the name, which may keep its own.
+    fn check_project_name(&self, name: &str, renamed: Option<i32>) -> Result<(), Error> {
+        if self.projects.values().any(|project| project.name == name && Some(project.id) != renamed) {
+            return Err(Error::Conflict(format!("a project named {name:?} already exists")));
+        }
+        Ok(())
+    }
+
+}
+
+fn is_open(task: &Task) -> bool {
+    !task.completed && task.archived_at.is_none()
+}
+
+fn by_due_date(mut tasks: Vec<Task>) -> Vec<Task> {
+    tasks.sort_by_key(|task| task.due_at);
+    tasks
+}
+
+// Saved responses by idempotency key, with the time they were saved.
+type SavedResponses = HashMap<String, (NaiveDateTime, Vec<u8>)>;
+
+// Holds the store lock for its whole lifetime, so transactions are serialized. Changes go to
+// `working` and only replace the committed tasks on commit.
+struct Transaction {
+    committed: OwnedMutexGuard<Tasks>,
+    working: Tasks,
+}
+
+// In-process backend for tests and demos. Everything is lost when the server stops.
+#[derive(Clone)]
+pub struct MemoryTaskStore {
+    inner: Arc<Mutex<Tasks>>,
+    transaction: Option<Arc<Mutex<Transaction>>>,
+    responses: Arc<Mutex<SavedResponses>>,
+}
+
+impl MemoryTaskStore {
+
+    pub fn new() -> Self {
+        let tasks = Tasks {
+            next_id: 1,
+            tasks: BTreeMap::new(),
+            next_project_id: 1,
+            projects: BTreeMap::new(),
+        };
+        Self {
+            inner: Arc::new(Mutex::new(tasks)),
+            transaction: None,
+            responses: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn with_tasks<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Tasks) -> R,
+    {
+        match &self.transaction {
+            Some(transaction) => f(&mut transaction.lock().await.working),
+            None => f(&mut *self.inner.lock().await),
+        }
+    }
+
+    fn take_transaction(self) -> Result<Option<Transaction>, Error> {
+        match self.transaction {
+            Some(transaction) => {
+                let transaction = Arc::try_unwrap(transaction)
+                    .map_err(|_| Error::Transaction("transaction is still in use".to_string()))?;
+                Ok(Some(transaction.into_inner()))
+            },
+            None => Ok(None),
+        }
+    }
+
+}
+
+impl Default for MemoryTaskStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskStore for MemoryTaskStore {
+
+    type Id = i32;
+    type Task = Task;
+
+    async fn begin(&self) -> Result<Self, Error> {
+        if self.transaction.is_some() {
+            return Err(Error::Transaction("a transaction is already open".to_string()));
+        }
+        let committed = self.inner.clone().lock_owned().await;
+        let working = committed.clone();
+        let transaction = Transaction {
+            committed,
+            working,
+        };
+        Ok(Self {
+            inner: self.inner.clone(),
+            transaction: Some(Arc::new(Mutex::new(transaction))),
+            responses: self.responses.clone(),
+        })
+    }
+
+    async fn commit(self) -> Result<(), Error> {
+        if let Some(Transaction { mut committed, working }) = self.take_transaction()? {
+            *committed = working;
+        }
+        Ok(())
+    }
+
+    async fn rollback(self) -> Result<(), Error> {
+        // Dropping the transaction throws away `working` and releases the store lock
+        self.take_transaction()?;
+        Ok(())
+    }
+
+    async fn new_task(&self, title: &str, priority: Priority, due_at: Option<NaiveDateTime>, project_id: Option<i32>) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            tasks.check_project(project_id)?;
+            let id = tasks.next_id;
+            tasks.next_id += 1;
+            let mut task = Task::new(id, title, priority, Utc::now().naive_utc());
+            task.due_at = due_at;
+            task.project_id = project_id;
+            tasks.tasks.insert(id, task.clone());
+            Ok(task)
+        }).await
+    }
+
+    async fn pending_tasks(&self) -> Result<Vec<Task>, Error> {
+        let pending = |task: &Task| !task.completed && task.archived_at.is_none();
+        Ok(self.with_tasks(|tasks| tasks.filter(pending)).await)
+    }
+
+    async fn done_tasks(&self) -> Result<Vec<Task>, Error> {
+        let done = |task: &Task| task.completed && task.archived_at.is_none();
+        Ok(self.with_tasks(|tasks| tasks.filter(done)).await)
+    }
+
+    async fn mark_task_done(&self, task_id: i32) -> Result<Task, Error> {
+        let now = Utc::now().naive_utc();
+        self.with_tasks(|tasks| {
+            if tasks.has_open_subtasks(task_id) {
+                return Err(Error::Conflict("the task still has open subtasks".to_string()));
+            }
+            if tasks.is_blocked(task_id) {
+                return Err(Error::Conflict("the task is still blocked by open tasks".to_string()));
+            }
+            tasks.update(task_id, |task| {
+                task.completed = true;
+                task.completed_at.get_or_insert(now);
+            })
+        }).await
+    }
+
+    async fn reopen_task(&self, task_id: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| {
+            task.completed = false;
+            task.completed_at = None;
+        })).await
+    }
+
+    async fn edit_task_title(&self, task_id: i32, title: &str) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.title = title.to_string())).await
+    }
+
+    async fn edit_task_priority(&self, task_id: i32, priority: Priority) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.priority = priority)).await
+    }
+
+    async fn query_task_by_id(&self, task_id: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.tasks.get(&task_id).cloned().ok_or(Error::NotFound)).await
+    }
+
+    // Subtasks go along with their parent, like `ON DELETE CASCADE` does in the SQL backends.
+    async fn delete_task(&self, task_id: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            let task = tasks.tasks.remove(&task_id).ok_or(Error::NotFound)?;
+            let mut deleted = tasks.descendants(task_id);
+            for id in &deleted {
+                tasks.tasks.remove(id);
+            }
+            deleted.push(task_id);
+            for other in tasks.tasks.values_mut() {
+                other.blocked_by.retain(|id| !deleted.contains(id));
+            }
+            Ok(task)
+        }).await
+    }
+
+    async fn archive_task(&self, task_id: i32) -> Result<Task, Error> {
+        let now = Utc::now().naive_utc();
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.archived_at = Some(now))).await
+    }
+
+    async fn unarchive_task(&self, task_id: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.archived_at = None)).await
+    }
+
+    async fn archived_tasks(&self) -> Result<Vec<Task>, Error> {
+        let archived = |task: &Task| task.archived_at.is_some();
+        Ok(self.with_tasks(|tasks| tasks.filter(archived)).await)
+    }
+
+    async fn set_due_date(&self, task_id: i32, due_at: Option<NaiveDateTime>) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.due_at = due_at)).await
+    }
+
+    async fn overdue_tasks(&self, now: NaiveDateTime) -> Result<Vec<Task>, Error> {
+        let overdue = |task: &Task| is_open(task) && task.due_at.is_some_and(|due_at| due_at < now);
+        Ok(by_due_date(self.with_tasks(|tasks| tasks.filter(overdue)).await))
+    }
+
+    async fn due_tasks(&self, from: NaiveDateTime, until: NaiveDateTime) -> Result<Vec<Task>, Error> {
+        let due = |task: &Task| is_open(task) && task.due_at.is_some_and(|due_at| (from..=until).contains(&due_at));
+        Ok(by_due_date(self.with_tasks(|tasks| tasks.filter(due)).await))
+    }
+
+    async fn add_tag(&self, task_id: i32, tag: &str) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| {
+            if let Err(index) = task.tags.binary_search_by(|t| t.as_str().cmp(tag)) {
+                task.tags.insert(index, tag.to_string());
+            }
+        })).await
+    }
+
+    async fn remove_tag(&self, task_id: i32, tag: &str) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.tags.retain(|t| t != tag))).await
+    }
+
+    async fn tasks_with_tag(&self, tags: &[String], matching: TagMatch) -> Result<Vec<Task>, Error> {
+        let tagged = |task: &Task| {
+            task.archived_at.is_none() && match matching {
+                TagMatch::All => tags.iter().all(|tag| task.tags.contains(tag)),
+                TagMatch::Any => tags.iter().any(|tag| task.tags.contains(tag)),
+            }
+        };
+        Ok(self.with_tasks(|tasks| tasks.filter(tagged)).await)
+    }
+
+    async fn new_project(&self, name: &str) -> Result<Project, Error> {
+        self.with_tasks(|tasks| {
+            tasks.check_project_name(name, None)?;
+            let id = tasks.next_project_id;
+            tasks.next_project_id += 1;
+            let project = Project {
+                id,
+                name: name.to_string(),
+                created_at: Utc::now().naive_utc(),
+            };
+            tasks.projects.insert(id, project.clone());
+            Ok(project)
+        }).await
+    }
+
+    async fn rename_project(&self, project_id: i32, name: &str) -> Result<Project, Error> {
+        self.with_tasks(|tasks| {
+            tasks.check_project_name(name, Some(project_id))?;
+            let project = tasks.projects.get_mut(&project_id).ok_or(Error::ProjectNotFound)?;
+            project.name = name.to_string();
+            Ok(project.clone())
+        }).await
+    }
+
+    async fn delete_project(&self, project_id: i32) -> Result<Project, Error> {
+        self.with_tasks(|tasks| {
+            let project = tasks.projects.remove(&project_id).ok_or(Error::ProjectNotFound)?;
+            for task in tasks.tasks.values_mut().filter(|task| task.project_id == Some(project_id)) {
+                task.project_id = None;
+            }
+            Ok(project)
+        }).await
+    }
+
+    async fn projects(&self) -> Result<Vec<Project>, Error> {
+        Ok(self.with_tasks(|tasks| tasks.projects.values().cloned().collect()).await)
+    }
+
+    async fn move_task(&self, task_id: i32, project_id: Option<i32>) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            tasks.check_project(project_id)?;
+            tasks.update(task_id, |task| task.project_id = project_id)
+        }).await
+    }
+
+    async fn project_tasks(&self, project_id: i32, completed: bool) -> Result<Vec<Task>, Error> {
+        let in_project = |task: &Task| {
+            task.project_id == Some(project_id) && task.completed == completed && task.archived_at.is_none()
+        };
+        Ok(self.with_tasks(|tasks| tasks.filter(in_project)).await)
+    }
+
+    async fn add_subtask(&self, parent_id: i32, title: &str, priority: Priority) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            let project_id = tasks.tasks.get(&parent_id).ok_or(Error::NotFound)?.project_id;
+            let id = tasks.next_id;
+            tasks.next_id += 1;
+            let mut task = Task::new(id, title, priority, Utc::now().naive_utc());
+            task.project_id = project_id;
+            task.parent_id = Some(parent_id);
+            tasks.tasks.insert(id, task.clone());
+            Ok(task)
+        }).await
+    }
+
+    async fn move_subtask(&self, task_id: i32, parent_id: Option<i32>) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            // Walk up from the new parent, the task must not be among its ancestors
+            let mut ancestor = parent_id;
+            while let Some(id) = ancestor {
+                if id == task_id {
+                    return Err(Error::Validation("A task can't be moved below itself or one of its subtasks".to_string()));
+                }
+                ancestor = tasks.tasks.get(&id).ok_or(Error::NotFound)?.parent_id;
+            }
+            tasks.update(task_id, |task| task.parent_id = parent_id)
+        }).await
+    }
+
+    async fn task_tree(&self, task_id: i32) -> Result<TaskTree<Task>, Error> {
+        self.with_tasks(|tasks| {
+            let root = tasks.tasks.get(&task_id).cloned().ok_or(Error::NotFound)?;
+            let descendants = tasks.descendants(task_id)
+                .into_iter()
+                .filter_map(|id| tasks.tasks.get(&id).cloned())
+                .collect();
+            Ok(TaskTree::build(root, descendants, |task| task.id, |task| task.parent_id))
+        }).await
+    }
+
+    async fn complete_finished_parents(&self, task_id: i32) -> Result<(), Error> {
+        let now = Utc::now().naive_utc();
+        self.with_tasks(|tasks| {
+            let mut current = task_id;
+            while let Some(parent_id) = tasks.tasks.get(&current).and_then(|task| task.parent_id) {
+                let parent_open = tasks.tasks.get(&parent_id).is_some_and(|parent| !parent.completed);
+                if !parent_open || tasks.has_open_subtasks(parent_id) || tasks.is_blocked(parent_id) {
+                    break;
+                }
+                tasks.update(parent_id, |parent| {
+                    parent.completed = true;
+                    parent.completed_at.get_or_insert(now);
+                })?;
+                current = parent_id;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn add_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| {
+            if !tasks.tasks.contains_key(&task_id) || !tasks.tasks.contains_key(&blocked_by) {
+                return Err(Error::NotFound);
+            }
+            if tasks.waits_on(blocked_by, task_id) {
+                return Err(Error::Validation("A task can't wait on itself, directly or through other tasks".to_string()));
+            }
+            tasks.update(task_id, |task| {
+                if let Err(index) = task.blocked_by.binary_search(&blocked_by) {
+                    task.blocked_by.insert(index, blocked_by);
+                }
+            })
+        }).await
+    }
+
+    async fn remove_dependency(&self, task_id: i32, blocked_by: i32) -> Result<Task, Error> {
+        self.with_tasks(|tasks| tasks.update(task_id, |task| task.blocked_by.retain(|id| *id != blocked_by))).await
+    }
+
+    async fn tasks_by_readiness(&self, ready: bool) -> Result<Vec<Task>, Error> {
+        Ok(self.with_tasks(|tasks| {
+            tasks.filter(|task| is_open(task) && tasks.is_blocked(task.id) != ready)
+        }).await)
+    }
+
+    async fn saved_response(&self, key: &str, since: NaiveDateTime) -> Result<Option<Vec<u8>>, Error> {
+        let responses = self.responses.lock().await;
+        Ok(responses
+            .get(key)
+            .filter(|(saved_at, _)| *saved_at > since)
+            .map(|(_, response)| response.clone()))
+    }
+
+    async fn save_response(&self, key: &str, response: &[u8], expired_before: NaiveDateTime) -> Result<(), Error> {
+        let mut responses = self.responses.lock().await;
+        responses.retain(|_, (saved_at, _)| *saved_at >= expired_before);
+        responses.insert(key.to_string(), (Utc::now().naive_utc(), response.to_vec()));
+        Ok(())
+    }
+
+    // Nothing to reach, the tasks live in this process
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        Vec::new()
+    }
+
+}
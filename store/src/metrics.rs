@@ -0,0 +1,172 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use prometheus::core::Collector;
+use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use net::ErrorKind;
+use crate::PoolStatus;
+
+// Upper bounds of the histogram buckets, Prometheus' usual ones for latencies in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const BATCH_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0];
+
+// One server's counters, rendered in the Prometheus text format by the `/metrics` endpoint.
+// Clones share the same values, so the connection handlers and the endpoint each get one.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    command_errors: IntCounterVec,
+    command_duration: HistogramVec,
+    request_commands: Histogram,
+    connections_total: IntCounter,
+    connections_active: IntGauge,
+    bytes_received: IntCounter,
+    bytes_sent: IntCounter,
+    // Only filled in for backends with a pool, empty families aren't rendered at all
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGaugeVec,
+}
+
+// The metric names and labels are fixed, so a failure here is a bug rather than something to handle.
+fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: prometheus::Result<C>) -> C {
+    let collector = collector.expect("metric definitions are valid");
+    registry.register(Box::new(collector.clone())).expect("metric names are unique");
+    collector
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        Self {
+            commands: register(&registry, IntCounterVec::new(
+                Opts::new("todo_commands_total", "Commands executed."),
+                &["command"],
+            )),
+            command_errors: register(&registry, IntCounterVec::new(
+                Opts::new("todo_command_errors_total", "Commands that failed, by error kind."),
+                &["command", "kind"],
+            )),
+            command_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("todo_command_duration_seconds", "Time spent running a command.").buckets(LATENCY_BUCKETS.to_vec()),
+                &["command"],
+            )),
+            request_commands: register(&registry, Histogram::with_opts(
+                HistogramOpts::new("todo_request_commands", "Commands per request.").buckets(BATCH_BUCKETS.to_vec()),
+            )),
+            connections_total: register(&registry, IntCounter::new("todo_connections_total", "Client connections accepted.")),
+            connections_active: register(&registry, IntGauge::new("todo_connections_active", "Client connections currently open.")),
+            bytes_received: register(&registry, IntCounter::new(
+                "todo_frame_bytes_received_total",
+                "Bytes read from clients, length prefixes included.",
+            )),
+            bytes_sent: register(&registry, IntCounter::new(
+                "todo_frame_bytes_sent_total",
+                "Bytes written to clients, length prefixes included.",
+            )),
+            pool_connections: register(&registry, IntGaugeVec::new(
+                Opts::new("todo_db_pool_connections", "Open database connections by state."),
+                &["state"],
+            )),
+            pool_max_connections: register(&registry, IntGaugeVec::new(
+                Opts::new("todo_db_pool_max_connections", "Most connections the pool opens."),
+                &[],
+            )),
+            registry,
+        }
+    }
+}
+
+impl Metrics {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn command(&self, name: &'static str, latency: Duration, error: Option<ErrorKind>) {
+        self.commands.with_label_values(&[name]).inc();
+        self.command_duration.with_label_values(&[name]).observe(latency.as_secs_f64());
+        if let Some(kind) = error {
+            self.command_errors.with_label_values(&[name, &format!("{:?}", kind)]).inc();
+        }
+    }
+
+    pub fn request(&self, commands: usize) {
+        self.request_commands.observe(commands as f64);
+    }
+
+    // Counts the connection as open until the guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard {
+        self.connections_total.inc();
+        self.connections_active.inc();
+        ConnectionGuard(self.connections_active.clone())
+    }
+
+    pub fn render(&self, pool: Option<PoolStatus>) -> String {
+        if let Some(pool) = pool {
+            self.pool_connections.with_label_values(&["idle"]).set(pool.idle.into());
+            self.pool_connections.with_label_values(&["in_use"]).set(pool.open.saturating_sub(pool.idle).into());
+            self.pool_max_connections.with_label_values(&[] as &[&str]).set(pool.max.into());
+        }
+        let mut out = String::new();
+        // Only fails on malformed metric families, which the registry never hands out
+        let _ = TextEncoder::new().encode_utf8(&self.registry.gather(), &mut out);
+        out
+    }
+
+}
+
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Counts the bytes going through a client connection.
+pub struct MeteredStream<S> {
+    inner: S,
+    received: IntCounter,
+    sent: IntCounter,
+}
+
+impl<S> MeteredStream<S> {
+    pub fn new(inner: S, metrics: &Metrics) -> Self {
+        Self {
+            inner,
+            received: metrics.bytes_received.clone(),
+            sent: metrics.bytes_sent.clone(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.received.inc_by(read as u64);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &result {
+            self.sent.inc_by(*written as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,126 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use net::{ClientRequest, Command, CommandResult, ErrorKind, Hello, HelloResponse, ServerResponse};
+use task_store::{serve, MemoryTaskStore, Metrics, ServerConfig};
+
+const MAX_FRAME_SIZE: usize = 1024;
+
+async fn start_server() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig { max_frame_size: MAX_FRAME_SIZE, ..ServerConfig::default() };
+    tokio::spawn(serve(listener, MemoryTaskStore::new(), config, Metrics::new()));
+    addr
+}
+
+async fn write_raw(stream: &mut TcpStream, len: u32, body: &[u8]) {
+    stream.write_all(&len.to_be_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+}
+
+async fn send<T: serde::Serialize>(stream: &mut TcpStream, message: &T) {
+    let bytes = bincode::serialize(message).unwrap();
+    write_raw(stream, bytes.len() as u32, &bytes).await;
+}
+
+async fn receive<T: serde::de::DeserializeOwned>(stream: &mut TcpStream) -> Option<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await.ok()?;
+    let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await.ok()?;
+    Some(bincode::deserialize(&buf).unwrap())
+}
+
+async fn connect(addr: std::net::SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &Hello::new()).await;
+    match receive::<HelloResponse>(&mut stream).await {
+        Some(HelloResponse::Accepted(_)) => stream,
+        other => panic!("handshake failed: {:?}", other),
+    }
+}
+
+async fn pending_tasks_works(stream: &mut TcpStream) {
+    send(stream, &ClientRequest::<i32>::new(&[Command::PendingTasks])).await;
+    let response: ServerResponse = receive(stream).await.expect("connection was dropped");
+    assert!(response.error().is_none());
+    assert!(matches!(response.get(0), Some(CommandResult::Success(_))));
+}
+
+#[tokio::test]
+async fn undecodable_request_gets_error_frame_and_connection_survives() {
+    let addr = start_server().await;
+    let mut stream = connect(addr).await;
+
+    write_raw(&mut stream, 8, &[0xff; 8]).await;
+    let response: ServerResponse = receive(&mut stream).await.expect("connection was dropped");
+    assert_eq!(response.error().map(|e| e.kind), Some(ErrorKind::MalformedRequest));
+    assert!(response.get(0).is_none());
+
+    pending_tasks_works(&mut stream).await;
+}
+
+#[tokio::test]
+async fn empty_frame_gets_error_frame() {
+    let addr = start_server().await;
+    let mut stream = connect(addr).await;
+
+    write_raw(&mut stream, 0, &[]).await;
+    let response: ServerResponse = receive(&mut stream).await.expect("connection was dropped");
+    assert_eq!(response.error().map(|e| e.kind), Some(ErrorKind::MalformedRequest));
+
+    pending_tasks_works(&mut stream).await;
+}
+
+#[tokio::test]
+async fn huge_inner_length_is_rejected_without_allocating() {
+    let addr = start_server().await;
+    let mut stream = connect(addr).await;
+
+    // A command vector claiming u64::MAX elements, inside a frame of acceptable size
+    write_raw(&mut stream, 8, &u64::MAX.to_le_bytes()).await;
+    let response: ServerResponse = receive(&mut stream).await.expect("connection was dropped");
+    assert_eq!(response.error().map(|e| e.kind), Some(ErrorKind::MalformedRequest));
+
+    pending_tasks_works(&mut stream).await;
+}
+
+#[tokio::test]
+async fn oversized_frame_gets_error_frame_and_is_closed() {
+    let addr = start_server().await;
+    let mut stream = connect(addr).await;
+
+    write_raw(&mut stream, u32::MAX, &[]).await;
+    let response: ServerResponse = receive(&mut stream).await.expect("no error frame was sent");
+    assert_eq!(response.error().map(|e| e.kind), Some(ErrorKind::MalformedRequest));
+    assert!(receive::<ServerResponse>(&mut stream).await.is_none());
+
+    // The server itself keeps going
+    let mut stream = connect(addr).await;
+    pending_tasks_works(&mut stream).await;
+}
+
+#[tokio::test]
+async fn garbage_handshake_is_rejected() {
+    let addr = start_server().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    write_raw(&mut stream, 5, b"hello").await;
+    match receive::<HelloResponse>(&mut stream).await {
+        Some(HelloResponse::Rejected { .. }) => {},
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+    assert!(receive::<HelloResponse>(&mut stream).await.is_none());
+}
+
+#[tokio::test]
+async fn truncated_frame_does_not_take_the_server_down() {
+    let addr = start_server().await;
+    let mut stream = connect(addr).await;
+
+    write_raw(&mut stream, 100, &[1, 2, 3]).await;
+    drop(stream);
+
+    let mut stream = connect(addr).await;
+    pending_tasks_works(&mut stream).await;
+}
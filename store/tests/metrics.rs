@@ -0,0 +1,89 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use net::{ClientRequest, Command, Hello, HelloResponse, Priority, ServerResponse};
+use task_store::{serve, serve_http, MemoryTaskStore, Metrics, ServerConfig};
+
+async fn send<T: serde::Serialize>(stream: &mut TcpStream, message: &T) {
+    let bytes = bincode::serialize(message).unwrap();
+    stream.write_all(&(bytes.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(&bytes).await.unwrap();
+}
+
+async fn receive<T: serde::de::DeserializeOwned>(stream: &mut TcpStream) -> T {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await.unwrap();
+    let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await.unwrap();
+    bincode::deserialize(&buf).unwrap()
+}
+
+async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn commands_show_up_in_metrics() {
+    let db = MemoryTaskStore::new();
+    let metrics = Metrics::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, db.clone(), ServerConfig::default(), metrics.clone()));
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = http.local_addr().unwrap();
+    tokio::spawn(serve_http(http, db, metrics));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &Hello::new()).await;
+    assert!(matches!(receive(&mut stream).await, HelloResponse::Accepted(_)));
+    let commands = [
+        Command::NewTask { title: "measure".to_string(), priority: Priority::Low, due_at: None, project_id: None },
+        Command::MarkTaskDone(12345),
+    ];
+    send(&mut stream, &ClientRequest::new(&commands)).await;
+    let _: ServerResponse = receive(&mut stream).await;
+
+    let response = get(http_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    // Each server counts on its own, so other tests running alongside don't show up here
+    assert!(response.contains("todo_commands_total{command=\"NewTask\"} 1\n"), "{}", response);
+    assert!(response.contains("todo_command_errors_total{command=\"MarkTaskDone\",kind=\"NotFound\"}"), "{}", response);
+    assert!(response.contains("todo_command_duration_seconds_bucket{command=\"NewTask\",le=\"+Inf\"}"), "{}", response);
+    assert!(response.contains("todo_request_commands_bucket{le=\"2\"}"), "{}", response);
+    assert!(response.contains("todo_connections_active 1"), "{}", response);
+    // The memory store has no pool to report on
+    assert!(!response.contains("todo_db_pool_connections"), "{}", response);
+
+    assert!(get(http_addr, "/elsewhere").await.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn health_endpoints() {
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = http.local_addr().unwrap();
+    tokio::spawn(serve_http(http, MemoryTaskStore::new(), Metrics::new()));
+
+    assert!(get(http_addr, "/health/live").await.starts_with("HTTP/1.1 200 OK\r\n"));
+    // Nothing to check on the memory store, so it is always ready
+    let response = get(http_addr, "/health/ready").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nok\n"), "{}", response);
+}
+
+#[tokio::test]
+async fn oversized_request_heads_are_refused() {
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = http.local_addr().unwrap();
+    tokio::spawn(serve_http(http, MemoryTaskStore::new(), Metrics::new()));
+
+    let padding = "x".repeat(16 * 1024);
+    let mut stream = TcpStream::connect(http_addr).await.unwrap();
+    let request = format!("GET /metrics HTTP/1.1\r\nHost: test\r\nX-Padding: {}\r\n\r\n", padding);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+}